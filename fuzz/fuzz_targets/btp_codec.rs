@@ -0,0 +1,42 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ilp;
+extern crate bytes;
+extern crate tokio_io;
+
+use std::io::Cursor;
+use bytes::BytesMut;
+use tokio_io::codec::Decoder;
+use ilp::btp_packet::{BtpPacket, BtpCodec};
+
+// The first byte picks how many bytes arrive at a time, the rest is the stream
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    let chunk_size = data[0] as usize + 1;
+    let stream = &data[1..];
+
+    let mut codec = BtpCodec;
+    let mut buffer = BytesMut::new();
+    let mut from_codec = Vec::new();
+    'chunks: for chunk in stream.chunks(chunk_size) {
+        buffer.extend_from_slice(chunk);
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(packet)) => from_codec.push(packet),
+                Ok(None) => break,
+                Err(_) => break 'chunks,
+            }
+        }
+    }
+
+    // Framing doesn't depend on how the bytes arrive, so the codec finds the same packets
+    // as reading the whole stream at once
+    let mut reader = Cursor::new(stream);
+    let mut from_reader = Vec::new();
+    while let Ok(packet) = BtpPacket::read_from(&mut reader) {
+        from_reader.push(packet);
+    }
+    assert_eq!(from_codec, from_reader);
+});
@@ -0,0 +1,24 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ilp;
+
+use ilp::btp_packet::{BtpPacket, BtpPacketRef, Serializable};
+
+fuzz_target!(|data: &[u8]| {
+    let strict = BtpPacket::from_bytes_strict(data);
+    let packet = match BtpPacket::from_bytes(data) {
+        Ok(packet) => packet,
+        Err(_) => {
+            assert!(strict.is_err());
+            return;
+        },
+    };
+    assert_eq!(BtpPacketRef::from_bytes(data).unwrap().to_owned(), packet);
+
+    // Decoding can accept things like sub-millisecond timestamps that we don't write, so
+    // only check that encoding is stable from the first re-encoding onwards
+    if let Ok(bytes) = packet.to_bytes() {
+        let decoded = BtpPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes().unwrap(), bytes);
+    }
+});
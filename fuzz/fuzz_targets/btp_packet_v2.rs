@@ -0,0 +1,17 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ilp;
+
+use ilp::btp_packet::Serializable;
+use ilp::btp_packet::v2::BtpPacket;
+
+fuzz_target!(|data: &[u8]| {
+    let packet = match BtpPacket::from_bytes(data) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    if let Ok(bytes) = packet.to_bytes() {
+        let decoded = BtpPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes().unwrap(), bytes);
+    }
+});
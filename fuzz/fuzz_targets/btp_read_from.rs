@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ilp;
+
+use std::io::Cursor;
+use ilp::btp_packet::{BtpPacket, Serializable};
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = Cursor::new(data);
+    loop {
+        let start = reader.position() as usize;
+        let packet = match BtpPacket::read_from(&mut reader) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        // Exactly one packet is consumed, and it decodes the same way on its own
+        let end = reader.position() as usize;
+        assert_eq!(BtpPacket::from_bytes(&data[start..end]).unwrap(), packet);
+    }
+});
@@ -0,0 +1,1670 @@
+use std;
+use std::str;
+use std::str::FromStr;
+use std::fmt;
+use std::string;
+use std::io::{Read, Write, Error as IoError, ErrorKind};
+use ilp_packet::oer::WriteOerExt;
+use std::io::{Cursor};
+use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+#[cfg(feature = "tokio")] use bytes::BytesMut;
+#[cfg(feature = "tokio")] use tokio_io::codec::{Encoder, Decoder};
+use chrono;
+use chrono::{DateTime, Utc, NaiveDate};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+// BTP 2.0. Everything at the top level of this module is BTP 1.0
+pub mod v2;
+
+const DATE_TIME_FORMAT: &str = "%Y%m%d%H%M%S%.3fZ";
+
+// Longer packets are refused as soon as their length prefix is read, before anything is
+// allocated for them, so a peer can't make us reserve memory just by claiming a huge length
+pub const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+// ASN.1 GeneralizedTime in UTC. Parses anything from YYYYMMDDHHZ to fractions of a second
+// with any number of digits, but always writes the canonical millisecond form
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct GeneralizedTime(pub DateTime<Utc>);
+
+impl GeneralizedTime {
+    pub fn from_bytes(bytes: &[u8]) -> Result<GeneralizedTime, Error> {
+        str::from_utf8(bytes)?.parse()
+    }
+
+    // Anything more precise than milliseconds is truncated
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl From<DateTime<Utc>> for GeneralizedTime {
+    fn from(date: DateTime<Utc>) -> Self {
+        GeneralizedTime(date)
+    }
+}
+
+impl fmt::Display for GeneralizedTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.naive_utc().format(DATE_TIME_FORMAT))
+    }
+}
+
+impl FromStr for GeneralizedTime {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<GeneralizedTime, Error> {
+        if !string.ends_with('Z') {
+            return Err(Error::Invalid("GeneralizedTime must be in UTC"));
+        }
+        let string = &string[..string.len() - 1];
+        let (whole, fraction) = match string.find(|c: char| c == '.' || c == ',') {
+            Some(index) => (&string[..index], Some(&string[index + 1..])),
+            None => (string, None),
+        };
+        if !whole.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::Invalid("GeneralizedTime must only contain digits"));
+        }
+        // The minutes and seconds are optional
+        if whole.len() != 10 && whole.len() != 12 && whole.len() != 14 {
+            return Err(Error::Invalid("GeneralizedTime has the wrong number of digits"));
+        }
+        let digits = |start: usize, end: usize| -> u32 {
+            if end <= whole.len() {
+                whole[start..end].parse().unwrap()
+            } else {
+                0
+            }
+        };
+
+        let nanos = match fraction {
+            Some(fraction) => {
+                if whole.len() != 14 {
+                    return Err(Error::Invalid("GeneralizedTime fractions are only supported for seconds"));
+                }
+                if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(Error::Invalid("GeneralizedTime fraction must be digits"));
+                }
+                // Pad or truncate to nanoseconds
+                let mut nanos_string = fraction.chars().take(9).collect::<String>();
+                while nanos_string.len() < 9 {
+                    nanos_string.push('0');
+                }
+                nanos_string.parse().unwrap()
+            },
+            None => 0,
+        };
+
+        NaiveDate::from_ymd_opt(digits(0, 4) as i32, digits(4, 6), digits(6, 8))
+            .and_then(|date| date.and_hms_nano_opt(digits(8, 10), digits(10, 12), digits(12, 14), nanos))
+            .map(|date| GeneralizedTime(DateTime::<Utc>::from_utc(date, Utc)))
+            .ok_or(Error::Invalid("GeneralizedTime is not a valid date"))
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: std::io::Error) {
+            description(err.description())
+            from()
+        }
+        Chrono(err: chrono::ParseError) {
+            description(err.description())
+            from()
+        }
+        Utf8(err: string::FromUtf8Error) {
+            description(err.description())
+            from()
+        }
+        Utf8Str(err: str::Utf8Error) {
+            description(err.description())
+            from()
+        }
+        Invalid(descr: &'static str) {
+            description(descr)
+        }
+        TrailingBytes(offset: usize) {
+            description("unexpected bytes after the end of the packet")
+            display("unexpected bytes after the end of the packet at offset {}", offset)
+        }
+        NotAscii(field: &'static str, offset: usize) {
+            description("field must be ASCII")
+            display("{} must be ASCII but has a non-ASCII byte at offset {}", field, offset)
+        }
+        Json(err: serde_json::Error) {
+            description(err.description())
+            from()
+        }
+        WrongContentType(expected: ContentType, actual: ContentType) {
+            description("protocol data has the wrong content type")
+            display("expected {:?} protocol data but it was {:?}", expected, actual)
+        }
+        UnknownContentType(content_type: u8, offset: usize) {
+            description("unknown content type")
+            display("unknown content type {} at offset {}", content_type, offset)
+        }
+        // offset is where the field starts
+        Truncated(field: &'static str, offset: usize) {
+            description("packet ends in the middle of a field")
+            display("packet ends in the middle of {}, which starts at offset {}", field, offset)
+        }
+        InvalidField(field: &'static str, offset: usize, err: Box<Error>) {
+            description("invalid field")
+            display("invalid {} at offset {}: {}", field, offset, err)
+            cause(&**err)
+        }
+    }
+}
+
+impl Error {
+    // Errors that already say where they happened are passed on as they are
+    fn at(field: &'static str, offset: usize, err: Error) -> Error {
+        match err {
+            Error::Io(ref io_err) if io_err.kind() == ErrorKind::UnexpectedEof => Error::Truncated(field, offset),
+            Error::TrailingBytes(_) | Error::NotAscii(..) | Error::UnknownContentType(..) |
+            Error::Truncated(..) | Error::InvalidField(..) => err,
+            err => Error::InvalidField(field, offset, Box::new(err)),
+        }
+    }
+}
+
+// Unknown values keep the original byte so relayed packets are re-encoded exactly
+#[derive(Debug, PartialEq, Clone)]
+pub enum ContentType {
+    ApplicationOctetStream,
+    TextPlainUtf8,
+    ApplicationJson,
+    Unknown(u8),
+}
+
+impl From<u8> for ContentType {
+    fn from(type_int: u8) -> Self {
+        match type_int {
+            0 => ContentType::ApplicationOctetStream,
+            1 => ContentType::TextPlainUtf8,
+            2 => ContentType::ApplicationJson,
+            _ => ContentType::Unknown(type_int),
+        }
+    }
+}
+
+impl From<ContentType> for u8 {
+    fn from(content_type: ContentType) -> Self {
+        match content_type {
+            ContentType::ApplicationOctetStream => 0,
+            ContentType::TextPlainUtf8 => 1,
+            ContentType::ApplicationJson => 2,
+            ContentType::Unknown(type_int) => type_int,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PacketType {
+    Response,
+    ErrorResponse,
+    Prepare,
+    Fulfill,
+    Reject,
+    Message,
+    Unknown(u8),
+}
+
+impl From<u8> for PacketType {
+    fn from(type_int: u8) -> Self {
+        match type_int {
+            1 => PacketType::Response,
+            2 => PacketType::ErrorResponse,
+            3 => PacketType::Prepare,
+            4 => PacketType::Fulfill,
+            5 => PacketType::Reject,
+            6 => PacketType::Message,
+            _ => PacketType::Unknown(type_int),
+        }
+    }
+}
+
+impl From<PacketType> for u8 {
+    fn from(packet_type: PacketType) -> Self {
+        match packet_type {
+            PacketType::Response => 1,
+            PacketType::ErrorResponse => 2,
+            PacketType::Prepare => 3,
+            PacketType::Fulfill => 4,
+            PacketType::Reject => 5,
+            PacketType::Message => 6,
+            PacketType::Unknown(type_int) => type_int,
+        }
+    }
+}
+
+pub trait Serializable<T> {
+    fn from_bytes(bytes: &[u8]) -> Result<T, Error>;
+    fn to_bytes(&self) -> Result<Vec<u8>, Error>;
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ProtocolData {
+    pub protocol_name: String,
+    pub content_type: ContentType,
+    pub data: Vec<u8>,
+}
+
+impl ProtocolData {
+    pub fn json<T: Serialize>(protocol_name: &str, value: &T) -> Result<ProtocolData, Error> {
+        Ok(ProtocolData {
+            protocol_name: protocol_name.to_string(),
+            content_type: ContentType::ApplicationJson,
+            data: serde_json::to_vec(value)?,
+        })
+    }
+
+    pub fn text(protocol_name: &str, text: &str) -> ProtocolData {
+        ProtocolData {
+            protocol_name: protocol_name.to_string(),
+            content_type: ContentType::TextPlainUtf8,
+            data: text.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn octet_stream(protocol_name: &str, data: Vec<u8>) -> ProtocolData {
+        ProtocolData {
+            protocol_name: protocol_name.to_string(),
+            content_type: ContentType::ApplicationOctetStream,
+            data,
+        }
+    }
+
+    // The serialized ILP packet that goes with a transfer
+    pub fn ilp(packet: Vec<u8>) -> ProtocolData {
+        ProtocolData::octet_stream("ilp", packet)
+    }
+
+    pub fn as_json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        self.check_content_type(ContentType::ApplicationJson)?;
+        Ok(serde_json::from_slice(&self.data)?)
+    }
+
+    pub fn as_text(&self) -> Result<&str, Error> {
+        self.check_content_type(ContentType::TextPlainUtf8)?;
+        Ok(str::from_utf8(&self.data)?)
+    }
+
+    fn check_content_type(&self, expected: ContentType) -> Result<(), Error> {
+        if self.content_type != expected {
+            return Err(Error::WrongContentType(expected, self.content_type.clone()));
+        }
+        Ok(())
+    }
+}
+
+// Returns the first entry for the protocol
+pub fn find_protocol<'a>(protocol_data: &'a [ProtocolData], protocol_name: &str) -> Option<&'a ProtocolData> {
+    protocol_data.iter().find(|p| p.protocol_name == protocol_name)
+}
+
+impl Serializable<ProtocolData> for ProtocolData {
+    fn from_bytes(bytes: &[u8]) -> Result<ProtocolData, Error> {
+        let mut reader = Cursor::new(bytes);
+        Ok(ProtocolDataRef::read_from(&mut reader, DecodeContext::new(DecodeMode::Lenient))?.to_owned())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        if !self.protocol_name.as_str().is_ascii() {
+            return Err(Error::Invalid("protocol_name must be ASCII"))
+        }
+        bytes.write_var_octet_string(self.protocol_name.as_bytes())?;
+        bytes.write_u8(u8::from(self.content_type.clone()))?;
+        bytes.write_var_octet_string(&self.data)?;
+        Ok(bytes)
+    }
+}
+
+// OER variable-length unsigned integer: a length byte followed by the value in as few
+// big-endian bytes as possible
+pub fn write_var_uint(bytes: &mut Vec<u8>, value: u64) -> Result<(), Error> {
+    let mut length = 1;
+    while length < 8 && value >> (length * 8) != 0 {
+        length += 1;
+    }
+    bytes.write_u8(length as u8)?;
+    bytes.write_uint::<BigEndian>(value, length)?;
+    Ok(())
+}
+
+pub fn read_var_uint(reader: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    let length = reader.read_u8()?;
+    read_uint_of_width(reader, length)
+}
+
+fn write_protocol_data(bytes: &mut Vec<u8>, protocol_data: &[ProtocolData]) -> Result<(), Error> {
+    write_var_uint(bytes, protocol_data.len() as u64)?;
+
+    for p in protocol_data {
+        bytes.write_all(&p.to_bytes()?)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PacketContents {
+    Response(Response),
+    ErrorResponse(ErrorResponse),
+    Prepare(Prepare),
+    Reject(Reject),
+    Fulfill(Fulfill),
+    Message(Message),
+    // The raw contents of packet types we don't understand, so they can be passed on as they are
+    Unknown(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BtpPacket {
+    pub packet_type: PacketType,
+    pub request_id: u32,
+    pub data: PacketContents,
+}
+
+impl Serializable<BtpPacket> for BtpPacket {
+    fn from_bytes(bytes: &[u8]) -> Result<BtpPacket, Error> {
+        Ok(BtpPacketRef::from_bytes(bytes)?.to_owned())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_u8(u8::from(self.packet_type.clone()))?;
+        bytes.write_u32::<BigEndian>(self.request_id)?;
+        let content_bytes: Vec<u8> = match self.data {
+            PacketContents::Response(ref contents) => contents.to_bytes()?,
+            PacketContents::ErrorResponse(ref contents) => contents.to_bytes()?,
+            PacketContents::Prepare(ref contents) => contents.to_bytes()?,
+            PacketContents::Fulfill(ref contents) => contents.to_bytes()?,
+            PacketContents::Reject(ref contents) => contents.to_bytes()?,
+            PacketContents::Message(ref contents) => contents.to_bytes()?,
+            PacketContents::Unknown(ref contents) => contents.to_vec(),
+        };
+        bytes.write_var_octet_string(&content_bytes)?;
+        Ok(bytes)
+    }
+}
+
+impl BtpPacket {
+    pub fn from_bytes_strict(bytes: &[u8]) -> Result<BtpPacket, Error> {
+        Ok(BtpPacketRef::from_bytes_strict(bytes)?.to_owned())
+    }
+
+    // Reads exactly one packet, leaving the reader at the start of the next one
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<BtpPacket, Error> {
+        let mut bytes = vec![0u8; 6];
+        reader.read_exact(&mut bytes)?;
+        let header_length = match packet_header_length(&bytes)? {
+            Some(header_length) => header_length,
+            None => return Err(Error::Invalid("could not read packet header")),
+        };
+        let mut length_prefix = vec![0u8; header_length - bytes.len()];
+        reader.read_exact(&mut length_prefix)?;
+        bytes.extend_from_slice(&length_prefix);
+        let total_length = packet_length(&bytes)?.unwrap();
+        let contents_length = (total_length - bytes.len()) as u64;
+        // Grows the buffer as bytes arrive instead of trusting the length prefix up front
+        reader.take(contents_length).read_to_end(&mut bytes)?;
+        if bytes.len() < total_length {
+            return Err(Error::Io(IoError::new(ErrorKind::UnexpectedEof, "stream ended in the middle of a packet")));
+        }
+        BtpPacket::from_bytes(&bytes)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(&self.to_bytes()?)?;
+        Ok(())
+    }
+}
+
+// The type, request id and length prefix of the contents. Returns None if there aren't
+// enough bytes to tell how long the header is
+fn packet_header_length(bytes: &[u8]) -> Result<Option<usize>, Error> {
+    if bytes.len() < 6 {
+        return Ok(None);
+    }
+    if bytes[5] & 0x80 == 0 {
+        return Ok(Some(6));
+    }
+    let length_prefix_length = (bytes[5] & 0x7f) as usize;
+    if length_prefix_length == 0 || length_prefix_length > 8 {
+        return Err(Error::Invalid("invalid length prefix"));
+    }
+    Ok(Some(6 + length_prefix_length))
+}
+
+// Returns None if there aren't enough bytes to tell how long the packet is, and an error if it
+// is longer than MAX_PACKET_SIZE
+fn packet_length(bytes: &[u8]) -> Result<Option<usize>, Error> {
+    let header_length = match packet_header_length(bytes)? {
+        Some(header_length) => header_length,
+        None => return Ok(None),
+    };
+    if bytes.len() < header_length {
+        return Ok(None);
+    }
+    let contents_length = if header_length == 6 {
+        bytes[5] as u64
+    } else {
+        Cursor::new(&bytes[6..header_length]).read_uint::<BigEndian>(header_length - 6)?
+    };
+    if contents_length > MAX_PACKET_SIZE as u64 {
+        return Err(Error::Invalid("packet is longer than MAX_PACKET_SIZE"));
+    }
+    match header_length.checked_add(contents_length as usize) {
+        Some(length) if length <= MAX_PACKET_SIZE => Ok(Some(length)),
+        _ => Err(Error::Invalid("packet is longer than MAX_PACKET_SIZE")),
+    }
+}
+
+// Frames BTP packets on any byte stream, such as a raw TCP connection
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default)]
+pub struct BtpCodec;
+
+#[cfg(feature = "tokio")]
+impl Decoder for BtpCodec {
+    type Item = BtpPacket;
+    type Error = Error;
+
+    // Fails as soon as the length prefix says the frame is over MAX_PACKET_SIZE, rather than
+    // buffering it
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BtpPacket>, Error> {
+        let length = match packet_length(src)? {
+            Some(length) if length <= src.len() => length,
+            _ => return Ok(None),
+        };
+        let bytes = src.split_to(length);
+        Ok(Some(BtpPacket::from_bytes(&bytes)?))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Encoder for BtpCodec {
+    type Item = BtpPacket;
+    type Error = Error;
+
+    fn encode(&mut self, packet: BtpPacket, dst: &mut BytesMut) -> Result<(), Error> {
+        dst.extend_from_slice(&packet.to_bytes()?);
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub protocol_data: Vec<ProtocolData>,
+}
+
+impl Serializable<Response> for Response {
+    fn from_bytes(bytes: &[u8]) -> Result<Response, Error> {
+        Ok(ResponseRef::from_bytes(bytes)?.to_owned())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        write_protocol_data(&mut bytes, &self.protocol_data)?;
+        Ok(bytes)
+    }
+}
+
+// Corresponds to BTP Error type
+#[derive(Debug, PartialEq)]
+pub struct ErrorResponse {
+    pub code: String, // 3 ASCII characters
+    pub name: String,
+    pub triggered_at: DateTime<Utc>,
+    pub data: String,
+    pub protocol_data: Vec<ProtocolData>,
+}
+
+impl Serializable<ErrorResponse> for ErrorResponse {
+    fn from_bytes(bytes: &[u8]) -> Result<ErrorResponse, Error> {
+        Ok(ErrorResponseRef::from_bytes(bytes)?.to_owned())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        if self.code.len() as u64 != 3 || !self.code.is_ascii() {
+            return Err(Error::Invalid("code must be 3 ASCII characters"));
+        }
+        bytes.write_all(&self.code.as_bytes()[..3])?;
+        bytes.write_var_octet_string(self.name.as_bytes())?;
+        bytes.write_var_octet_string(&GeneralizedTime(self.triggered_at).to_bytes())?;
+        bytes.write_var_octet_string(self.data.as_bytes())?;
+        write_protocol_data(&mut bytes, &self.protocol_data)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Prepare {
+    pub transfer_id: [u8; 16],
+    pub amount: u64,
+    pub execution_condition: [u8; 32],
+    pub expires_at: DateTime<Utc>,
+    pub protocol_data: Vec<ProtocolData>,
+}
+
+impl Serializable<Prepare> for Prepare {
+    fn from_bytes(bytes: &[u8]) -> Result<Prepare, Error> {
+        Ok(PrepareRef::from_bytes(bytes)?.to_owned())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_all(&self.transfer_id)?;
+        bytes.write_u64::<BigEndian>(self.amount)?;
+        bytes.write_all(&self.execution_condition)?;
+        bytes.write_var_octet_string(&GeneralizedTime(self.expires_at).to_bytes())?;
+        write_protocol_data(&mut bytes, &self.protocol_data)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Fulfill {
+    pub transfer_id: [u8; 16],
+    pub fulfillment: [u8; 32],
+    pub protocol_data: Vec<ProtocolData>,
+}
+
+impl Serializable<Fulfill> for Fulfill {
+    fn from_bytes(bytes: &[u8]) -> Result<Fulfill, Error> {
+        Ok(FulfillRef::from_bytes(bytes)?.to_owned())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_all(&self.transfer_id)?;
+        bytes.write_all(&self.fulfillment)?;
+        write_protocol_data(&mut bytes, &self.protocol_data)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Reject {
+    pub transfer_id: [u8; 16],
+    pub protocol_data: Vec<ProtocolData>,
+}
+
+impl Serializable<Reject> for Reject {
+    fn from_bytes(bytes: &[u8]) -> Result<Reject, Error> {
+        Ok(RejectRef::from_bytes(bytes)?.to_owned())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_all(&self.transfer_id)?;
+        write_protocol_data(&mut bytes, &self.protocol_data)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub protocol_data: Vec<ProtocolData>,
+}
+
+impl Serializable<Message> for Message {
+    fn from_bytes(bytes: &[u8]) -> Result<Message, Error> {
+        Ok(MessageRef::from_bytes(bytes)?.to_owned())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        write_protocol_data(&mut bytes, &self.protocol_data)?;
+        Ok(bytes)
+    }
+}
+
+// Borrowed views of the packets above, for forwarding packets without copying the protocol data.
+// The owned decoders go through these so both validate the same way
+
+// Strict mode rejects things the lenient decoder lets through, like trailing bytes, so we can
+// catch misbehaving peers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DecodeMode {
+    Lenient,
+    Strict,
+}
+
+// offset is where the bytes being read start in the whole packet, so errors can point at
+// the exact byte
+#[derive(Clone, Copy)]
+struct DecodeContext {
+    mode: DecodeMode,
+    offset: usize,
+}
+
+impl DecodeContext {
+    fn new(mode: DecodeMode) -> Self {
+        DecodeContext {
+            mode,
+            offset: 0,
+        }
+    }
+
+    fn strict(&self) -> bool {
+        self.mode == DecodeMode::Strict
+    }
+
+    fn position(&self, reader: &Cursor<&[u8]>) -> usize {
+        self.offset + reader.position() as usize
+    }
+
+    // For a field that was just read and ended at the reader's current position
+    fn field_position(&self, reader: &Cursor<&[u8]>, field: &[u8]) -> usize {
+        self.position(reader) - field.len()
+    }
+
+    // Reads one field. In strict mode any error says which field it was and where it starts
+    fn read<'a, T, E, F>(&self, field: &'static str, reader: &mut Cursor<&'a [u8]>, read: F) -> Result<T, Error>
+        where F: FnOnce(&mut Cursor<&'a [u8]>) -> Result<T, E>, Error: From<E>
+    {
+        let start = self.position(reader);
+        read(reader).map_err(|err| {
+            let err = Error::from(err);
+            if self.strict() { Error::at(field, start, err) } else { err }
+        })
+    }
+
+    fn check_ascii(&self, field_name: &'static str, reader: &Cursor<&[u8]>, field: &[u8]) -> Result<(), Error> {
+        if self.strict() {
+            if let Some(index) = field.iter().position(|b| !b.is_ascii()) {
+                return Err(Error::NotAscii(field_name, self.field_position(reader, field) + index));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_end(&self, reader: &Cursor<&[u8]>) -> Result<(), Error> {
+        if self.strict() && (reader.position() as usize) < reader.get_ref().len() {
+            return Err(Error::TrailingBytes(self.position(reader)));
+        }
+        Ok(())
+    }
+}
+
+// byteorder panics on widths outside 1 to 8 bytes, which hostile input could otherwise trigger
+fn read_uint_of_width(reader: &mut Cursor<&[u8]>, width: u8) -> Result<u64, Error> {
+    if width == 0 || width > 8 {
+        return Err(Error::Invalid("integer must be between 1 and 8 bytes wide"));
+    }
+    Ok(reader.read_uint::<BigEndian>(width as usize)?)
+}
+
+// Borrows the next length bytes, failing like read_exact if there aren't that many left
+fn read_slice<'a>(reader: &mut Cursor<&'a [u8]>, length: u64) -> Result<&'a [u8], Error> {
+    let bytes: &'a [u8] = *reader.get_ref();
+    let start = reader.position() as usize;
+    if length > (bytes.len() - start) as u64 {
+        return Err(Error::Io(IoError::new(ErrorKind::UnexpectedEof, "field is longer than the remaining bytes")));
+    }
+    let end = start + length as usize;
+    reader.set_position(end as u64);
+    Ok(&bytes[start..end])
+}
+
+// Checks the length against the bytes that are left, so it's safe to use on untrusted input
+pub fn read_var_octet_slice<'a>(reader: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let length = reader.read_u8()?;
+    let length = if length & 0x80 != 0 {
+        read_uint_of_width(reader, length & 0x7f)?
+    } else {
+        length as u64
+    };
+    read_slice(reader, length)
+}
+
+fn read_var_octet_str<'a>(reader: &mut Cursor<&'a [u8]>) -> Result<&'a str, Error> {
+    Ok(str::from_utf8(read_var_octet_slice(reader)?)?)
+}
+
+fn read_generalized_time(reader: &mut Cursor<&[u8]>) -> Result<DateTime<Utc>, Error> {
+    Ok(GeneralizedTime::from_bytes(read_var_octet_slice(reader)?)?.0)
+}
+
+// Protocol data always comes last, so in strict mode nothing may follow it
+fn read_protocol_data_ref<'a>(reader: &mut Cursor<&'a [u8]>, context: DecodeContext) -> Result<Vec<ProtocolDataRef<'a>>, Error> {
+    let length_prefix = context.read("protocol_data", reader, read_var_uint)?;
+    let mut data: Vec<ProtocolDataRef<'a>> = Vec::new();
+    for _i in 0..length_prefix {
+        data.push(ProtocolDataRef::read_from(reader, context)?);
+    }
+    context.check_end(reader)?;
+    Ok(data)
+}
+
+fn protocol_data_to_owned(protocol_data: &[ProtocolDataRef]) -> Vec<ProtocolData> {
+    protocol_data.iter().map(|p| p.to_owned()).collect()
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProtocolDataRef<'a> {
+    pub protocol_name: &'a str,
+    pub content_type: ContentType,
+    pub data: &'a [u8],
+}
+
+impl<'a> ProtocolDataRef<'a> {
+    fn read_from(reader: &mut Cursor<&'a [u8]>, context: DecodeContext) -> Result<ProtocolDataRef<'a>, Error> {
+        let protocol_name_bytes = context.read("protocol_name", reader, read_var_octet_slice)?;
+        context.check_ascii("protocol_name", reader, protocol_name_bytes)?;
+        let protocol_name = str::from_utf8(protocol_name_bytes)?;
+        let content_type_byte = context.read("content_type", reader, |reader| reader.read_u8())?;
+        let content_type = ContentType::from(content_type_byte);
+        if let ContentType::Unknown(content_type_byte) = content_type {
+            if context.strict() {
+                return Err(Error::UnknownContentType(content_type_byte, context.position(reader) - 1));
+            }
+        }
+        let data = context.read("data", reader, read_var_octet_slice)?;
+        Ok(ProtocolDataRef {
+            protocol_name,
+            content_type,
+            data,
+        })
+    }
+
+    pub fn to_owned(&self) -> ProtocolData {
+        ProtocolData {
+            protocol_name: self.protocol_name.to_string(),
+            content_type: self.content_type.clone(),
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PacketContentsRef<'a> {
+    Response(ResponseRef<'a>),
+    ErrorResponse(ErrorResponseRef<'a>),
+    Prepare(PrepareRef<'a>),
+    Reject(RejectRef<'a>),
+    Fulfill(FulfillRef<'a>),
+    Message(MessageRef<'a>),
+    Unknown(&'a [u8]),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BtpPacketRef<'a> {
+    pub packet_type: PacketType,
+    pub request_id: u32,
+    pub data: PacketContentsRef<'a>,
+}
+
+impl<'a> BtpPacketRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<BtpPacketRef<'a>, Error> {
+        BtpPacketRef::from_bytes_with_mode(bytes, DecodeMode::Lenient)
+    }
+
+    pub fn from_bytes_strict(bytes: &'a [u8]) -> Result<BtpPacketRef<'a>, Error> {
+        BtpPacketRef::from_bytes_with_mode(bytes, DecodeMode::Strict)
+    }
+
+    pub fn from_bytes_with_mode(bytes: &'a [u8], mode: DecodeMode) -> Result<BtpPacketRef<'a>, Error> {
+        let context = DecodeContext::new(mode);
+        let mut reader = Cursor::new(bytes);
+        let packet_type = PacketType::from(context.read("packet_type", &mut reader, |reader| reader.read_u8())?);
+        let request_id = context.read("request_id", &mut reader, |reader| reader.read_u32::<BigEndian>())?;
+        let content_bytes = context.read("contents", &mut reader, read_var_octet_slice)?;
+        context.check_end(&reader)?;
+        let content_context = DecodeContext {
+            mode,
+            offset: context.field_position(&reader, content_bytes),
+        };
+        let data = match packet_type {
+            PacketType::Response => PacketContentsRef::Response(ResponseRef::decode(content_bytes, content_context)?),
+            PacketType::ErrorResponse => PacketContentsRef::ErrorResponse(ErrorResponseRef::decode(content_bytes, content_context)?),
+            PacketType::Prepare => PacketContentsRef::Prepare(PrepareRef::decode(content_bytes, content_context)?),
+            PacketType::Fulfill => PacketContentsRef::Fulfill(FulfillRef::decode(content_bytes, content_context)?),
+            PacketType::Reject => PacketContentsRef::Reject(RejectRef::decode(content_bytes, content_context)?),
+            PacketType::Message => PacketContentsRef::Message(MessageRef::decode(content_bytes, content_context)?),
+            PacketType::Unknown(_) => PacketContentsRef::Unknown(content_bytes),
+        };
+        Ok(BtpPacketRef {
+            packet_type,
+            request_id,
+            data,
+        })
+    }
+
+    pub fn to_owned(&self) -> BtpPacket {
+        let data = match self.data {
+            PacketContentsRef::Response(ref contents) => PacketContents::Response(contents.to_owned()),
+            PacketContentsRef::ErrorResponse(ref contents) => PacketContents::ErrorResponse(contents.to_owned()),
+            PacketContentsRef::Prepare(ref contents) => PacketContents::Prepare(contents.to_owned()),
+            PacketContentsRef::Fulfill(ref contents) => PacketContents::Fulfill(contents.to_owned()),
+            PacketContentsRef::Reject(ref contents) => PacketContents::Reject(contents.to_owned()),
+            PacketContentsRef::Message(ref contents) => PacketContents::Message(contents.to_owned()),
+            PacketContentsRef::Unknown(contents) => PacketContents::Unknown(contents.to_vec()),
+        };
+        BtpPacket {
+            packet_type: self.packet_type.clone(),
+            request_id: self.request_id,
+            data,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResponseRef<'a> {
+    pub protocol_data: Vec<ProtocolDataRef<'a>>,
+}
+
+impl<'a> ResponseRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<ResponseRef<'a>, Error> {
+        ResponseRef::decode(bytes, DecodeContext::new(DecodeMode::Lenient))
+    }
+
+    fn decode(bytes: &'a [u8], context: DecodeContext) -> Result<ResponseRef<'a>, Error> {
+        let mut reader = Cursor::new(bytes);
+        let protocol_data = read_protocol_data_ref(&mut reader, context)?;
+        Ok(ResponseRef {
+            protocol_data,
+        })
+    }
+
+    pub fn to_owned(&self) -> Response {
+        Response {
+            protocol_data: protocol_data_to_owned(&self.protocol_data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ErrorResponseRef<'a> {
+    pub code: &'a str,
+    pub name: &'a str,
+    pub triggered_at: DateTime<Utc>,
+    pub data: &'a str,
+    pub protocol_data: Vec<ProtocolDataRef<'a>>,
+}
+
+impl<'a> ErrorResponseRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<ErrorResponseRef<'a>, Error> {
+        ErrorResponseRef::decode(bytes, DecodeContext::new(DecodeMode::Lenient))
+    }
+
+    fn decode(bytes: &'a [u8], context: DecodeContext) -> Result<ErrorResponseRef<'a>, Error> {
+        let mut reader = Cursor::new(bytes);
+        let code_bytes = context.read("code", &mut reader, |reader| read_slice(reader, 3))?;
+        context.check_ascii("code", &reader, code_bytes)?;
+        let code = str::from_utf8(code_bytes)?;
+        let name_bytes = context.read("name", &mut reader, read_var_octet_slice)?;
+        context.check_ascii("name", &reader, name_bytes)?;
+        let name = str::from_utf8(name_bytes)?;
+        let triggered_at = context.read("triggered_at", &mut reader, read_generalized_time)?;
+        let data = context.read("data", &mut reader, read_var_octet_str)?;
+        let protocol_data = read_protocol_data_ref(&mut reader, context)?;
+        Ok(ErrorResponseRef {
+            code,
+            name,
+            triggered_at,
+            data,
+            protocol_data,
+        })
+    }
+
+    pub fn to_owned(&self) -> ErrorResponse {
+        ErrorResponse {
+            code: self.code.to_string(),
+            name: self.name.to_string(),
+            triggered_at: self.triggered_at,
+            data: self.data.to_string(),
+            protocol_data: protocol_data_to_owned(&self.protocol_data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PrepareRef<'a> {
+    pub transfer_id: [u8; 16],
+    pub amount: u64,
+    pub execution_condition: [u8; 32],
+    pub expires_at: DateTime<Utc>,
+    pub protocol_data: Vec<ProtocolDataRef<'a>>,
+}
+
+impl<'a> PrepareRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<PrepareRef<'a>, Error> {
+        PrepareRef::decode(bytes, DecodeContext::new(DecodeMode::Lenient))
+    }
+
+    fn decode(bytes: &'a [u8], context: DecodeContext) -> Result<PrepareRef<'a>, Error> {
+        let mut reader = Cursor::new(bytes);
+        let mut transfer_id = [0u8; 16];
+        context.read("transfer_id", &mut reader, |reader| reader.read_exact(&mut transfer_id))?;
+        let amount = context.read("amount", &mut reader, |reader| reader.read_u64::<BigEndian>())?;
+        let mut execution_condition = [0u8; 32];
+        context.read("execution_condition", &mut reader, |reader| reader.read_exact(&mut execution_condition))?;
+        let expires_at = context.read("expires_at", &mut reader, read_generalized_time)?;
+        let protocol_data = read_protocol_data_ref(&mut reader, context)?;
+        Ok(PrepareRef {
+            transfer_id,
+            amount,
+            execution_condition,
+            expires_at,
+            protocol_data,
+        })
+    }
+
+    pub fn to_owned(&self) -> Prepare {
+        Prepare {
+            transfer_id: self.transfer_id,
+            amount: self.amount,
+            execution_condition: self.execution_condition,
+            expires_at: self.expires_at,
+            protocol_data: protocol_data_to_owned(&self.protocol_data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FulfillRef<'a> {
+    pub transfer_id: [u8; 16],
+    pub fulfillment: [u8; 32],
+    pub protocol_data: Vec<ProtocolDataRef<'a>>,
+}
+
+impl<'a> FulfillRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<FulfillRef<'a>, Error> {
+        FulfillRef::decode(bytes, DecodeContext::new(DecodeMode::Lenient))
+    }
+
+    fn decode(bytes: &'a [u8], context: DecodeContext) -> Result<FulfillRef<'a>, Error> {
+        let mut reader = Cursor::new(bytes);
+        let mut transfer_id = [0u8; 16];
+        context.read("transfer_id", &mut reader, |reader| reader.read_exact(&mut transfer_id))?;
+        let mut fulfillment = [0u8; 32];
+        context.read("fulfillment", &mut reader, |reader| reader.read_exact(&mut fulfillment))?;
+        let protocol_data = read_protocol_data_ref(&mut reader, context)?;
+        Ok(FulfillRef {
+            transfer_id,
+            fulfillment,
+            protocol_data,
+        })
+    }
+
+    pub fn to_owned(&self) -> Fulfill {
+        Fulfill {
+            transfer_id: self.transfer_id,
+            fulfillment: self.fulfillment,
+            protocol_data: protocol_data_to_owned(&self.protocol_data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RejectRef<'a> {
+    pub transfer_id: [u8; 16],
+    pub protocol_data: Vec<ProtocolDataRef<'a>>,
+}
+
+impl<'a> RejectRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<RejectRef<'a>, Error> {
+        RejectRef::decode(bytes, DecodeContext::new(DecodeMode::Lenient))
+    }
+
+    fn decode(bytes: &'a [u8], context: DecodeContext) -> Result<RejectRef<'a>, Error> {
+        let mut reader = Cursor::new(bytes);
+        let mut transfer_id = [0u8; 16];
+        context.read("transfer_id", &mut reader, |reader| reader.read_exact(&mut transfer_id))?;
+        let protocol_data = read_protocol_data_ref(&mut reader, context)?;
+        Ok(RejectRef {
+            transfer_id,
+            protocol_data,
+        })
+    }
+
+    pub fn to_owned(&self) -> Reject {
+        Reject {
+            transfer_id: self.transfer_id,
+            protocol_data: protocol_data_to_owned(&self.protocol_data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageRef<'a> {
+    pub protocol_data: Vec<ProtocolDataRef<'a>>,
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<MessageRef<'a>, Error> {
+        MessageRef::decode(bytes, DecodeContext::new(DecodeMode::Lenient))
+    }
+
+    fn decode(bytes: &'a [u8], context: DecodeContext) -> Result<MessageRef<'a>, Error> {
+        let mut reader = Cursor::new(bytes);
+        let protocol_data = read_protocol_data_ref(&mut reader, context)?;
+        Ok(MessageRef {
+            protocol_data,
+        })
+    }
+
+    pub fn to_owned(&self) -> Message {
+        Message {
+            protocol_data: protocol_data_to_owned(&self.protocol_data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod generalized_time {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn serialize() {
+        let date1 = Utc.timestamp(0, 0);
+        let actual1 = GeneralizedTime(date1).to_bytes();
+        let expected1 = [ 49, 57, 55, 48, 48, 49, 48, 49, 48, 48, 48, 48, 48, 48, 46, 48, 48, 48, 90 ];
+        assert_eq!(actual1, expected1);
+
+        let date2 = Utc.timestamp(1_505_444_840, 870_000_000);
+        let actual2 = GeneralizedTime(date2).to_bytes();
+        let expected2 = [ 50, 48, 49, 55, 48, 57, 49, 53, 48, 51, 48, 55, 50, 48, 46, 56, 55, 48, 90 ];
+        assert_eq!(actual2, expected2);
+    }
+
+    #[test]
+    fn deserialize() {
+        let expected1 = Utc.timestamp(0, 0);
+        let actual1 = GeneralizedTime::from_bytes(&[ 49, 57, 55, 48, 48, 49, 48, 49, 48, 48, 48, 48, 48, 48, 46, 48, 48, 48, 90 ]).unwrap().0;
+        assert_eq!(actual1, expected1);
+
+        let expected2 = Utc.timestamp(1_505_444_840, 870_000_000);
+        let actual2 = GeneralizedTime::from_bytes(&[ 50, 48, 49, 55, 48, 57, 49, 53, 48, 51, 48, 55, 50, 48, 46, 56, 55, 48, 90 ]).unwrap().0;
+        assert_eq!(actual2, expected2);
+    }
+
+    #[test]
+    fn deserialize_variants() {
+        let parse = |string: &str| GeneralizedTime::from_str(string).unwrap().0;
+        assert_eq!(parse("20170915030720Z"), Utc.timestamp(1_505_444_840, 0));
+        assert_eq!(parse("20170915030720.8Z"), Utc.timestamp(1_505_444_840, 800_000_000));
+        assert_eq!(parse("20170915030720,87Z"), Utc.timestamp(1_505_444_840, 870_000_000));
+        assert_eq!(parse("20170915030720.870123Z"), Utc.timestamp(1_505_444_840, 870_123_000));
+        assert_eq!(parse("201709150307Z"), Utc.timestamp(1_505_444_820, 0));
+        assert_eq!(parse("2017091503Z"), Utc.timestamp(1_505_444_400, 0));
+        assert!(GeneralizedTime::from_str("20170915030720.870").is_err());
+        assert!(GeneralizedTime::from_str("20170915030720.Z").is_err());
+        assert!(GeneralizedTime::from_str("20171315030720Z").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_milliseconds(secs in 0i64..253_402_300_800, millis in 0u32..1000) {
+            let date = Utc.timestamp(secs, millis * 1_000_000);
+            assert_eq!(GeneralizedTime::from_bytes(&GeneralizedTime(date).to_bytes()).unwrap().0, date);
+        }
+
+        #[test]
+        fn parses_any_fraction_and_writes_canonical_form(secs in 0i64..253_402_300_800, nanos in 0u32..1_000_000_000, num_digits in 0usize..10) {
+            let date = Utc.timestamp(secs, nanos);
+            let fraction = format!("{:09}", nanos)[..num_digits].to_string();
+            let string = if num_digits > 0 {
+                format!("{}.{}Z", date.format("%Y%m%d%H%M%S"), fraction)
+            } else {
+                format!("{}Z", date.format("%Y%m%d%H%M%S"))
+            };
+            let divisor = 10u32.pow(9 - num_digits as u32);
+            let expected = Utc.timestamp(secs, nanos / divisor * divisor);
+
+            let parsed = GeneralizedTime::from_str(&string).unwrap();
+            assert_eq!(parsed.0, expected);
+            let canonical = format!("{}.{:03}Z", date.format("%Y%m%d%H%M%S"), expected.timestamp_subsec_millis());
+            assert_eq!(parsed.to_bytes(), canonical.into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod btp_prepare {
+    use super::*;
+
+    fn get_instance1() -> BtpPacket {
+        BtpPacket {
+            packet_type: PacketType::Prepare,
+            request_id: 1,
+            data: PacketContents::Prepare(Prepare {
+                transfer_id: [180,200,56,246,128,177,71,248,168,46,177,252,251,237,137,213],
+                amount: 1000,
+                execution_condition: [219, 42, 249, 249, 219, 166, 255, 52, 179, 237, 173, 251, 152, 107, 155, 180, 205, 75, 75, 65, 229, 4, 65, 25, 197, 93, 52, 175, 218, 191, 252, 2],
+                expires_at: DateTime::parse_from_rfc3339("2017-08-28T09:32:00.000Z").unwrap().with_timezone(&Utc),
+                protocol_data: vec![
+                    ProtocolData {
+                        protocol_name: String::from("ilp"),
+                        content_type: ContentType::ApplicationOctetStream,
+                        data: vec![1,28,0,0,0,0,0,0,0,100,17,101,120,97,109,112,108,101,46,114,101,100,46,97,108,105,99,101,0,0]
+                    },
+                    ProtocolData {
+                        protocol_name: "foo".to_string(),
+                        content_type: ContentType::ApplicationOctetStream,
+                        data: b"bar".to_vec()
+                    },
+                    ProtocolData {
+                        protocol_name: "beep".to_string(),
+                        content_type: ContentType::TextPlainUtf8,
+                        data: b"boop".to_vec()
+                    },
+                    ProtocolData {
+                        protocol_name: "json".to_string(),
+                        content_type: ContentType::ApplicationJson,
+                        data: b"{}".to_vec()
+                    }
+                ],
+            })
+        }
+    }
+
+    fn get_bytes1() -> Vec<u8> {
+        vec![3, 0, 0, 0, 1, 129, 143, 180, 200, 56, 246, 128, 177, 71, 248, 168, 46, 177, 252, 251, 237, 137, 213, 0, 0, 0, 0, 0, 0, 3, 232, 219, 42, 249, 249, 219, 166, 255, 52, 179, 237, 173, 251, 152, 107, 155, 180, 205, 75, 75, 65, 229, 4, 65, 25, 197, 93, 52, 175, 218, 191, 252, 2, 19, 50, 48, 49, 55, 48, 56, 50, 56, 48, 57, 51, 50, 48, 48, 46, 48, 48, 48, 90, 1, 4, 3, 105, 108, 112, 0, 30, 1, 28, 0, 0, 0, 0, 0, 0, 0, 100, 17, 101, 120, 97, 109, 112, 108, 101, 46, 114, 101, 100, 46, 97, 108, 105, 99, 101, 0, 0, 3, 102, 111, 111, 0, 3, 98, 97, 114, 4, 98, 101, 101, 112, 1, 4, 98, 111, 111, 112, 4, 106, 115, 111, 110, 2, 2, 123, 125]
+    }
+
+    #[test]
+    fn serialize() {
+        assert_eq!(get_instance1().to_bytes().unwrap(), get_bytes1());
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(BtpPacket::from_bytes(&get_bytes1()).unwrap(), get_instance1());
+    }
+
+    #[test]
+    fn deserialize_borrowed() {
+        let bytes = get_bytes1();
+        let packet = BtpPacketRef::from_bytes(&bytes).unwrap();
+        match packet.data {
+            PacketContentsRef::Prepare(ref prepare) => {
+                assert_eq!(prepare.protocol_data[1].protocol_name, "foo");
+                assert_eq!(prepare.protocol_data[1].data, b"bar");
+                // Points into the input rather than a copy
+                let data_start = prepare.protocol_data[0].data.as_ptr() as usize;
+                assert!(data_start >= bytes.as_ptr() as usize && data_start < bytes.as_ptr() as usize + bytes.len());
+            },
+            ref other => panic!("expected prepare, got {:?}", other),
+        }
+        assert_eq!(packet.to_owned(), get_instance1());
+    }
+
+    #[test]
+    fn read_from_stream() {
+        let mut bytes = get_bytes1();
+        bytes.extend(get_bytes1());
+        let mut reader = Cursor::new(bytes);
+        assert_eq!(BtpPacket::read_from(&mut reader).unwrap(), get_instance1());
+        assert_eq!(BtpPacket::read_from(&mut reader).unwrap(), get_instance1());
+        assert!(BtpPacket::read_from(&mut reader).is_err());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn codec_waits_for_whole_packet() {
+        let bytes = get_bytes1();
+        let mut codec = BtpCodec;
+        let mut buffer = BytesMut::from(&bytes[..50]);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&bytes[50..]);
+        buffer.extend_from_slice(&bytes[..3]);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(get_instance1()));
+        assert_eq!(buffer.len(), 3);
+
+        let mut encoded = BytesMut::new();
+        codec.encode(get_instance1(), &mut encoded).unwrap();
+        assert_eq!(&encoded[..], &bytes[..]);
+    }
+
+    // Prepare headers whose length prefix claims too much, and one right at the limit
+    fn oversized_headers() -> (Vec<Vec<u8>>, Vec<u8>) {
+        // An 8 byte length prefix claiming u64::MAX bytes of contents
+        let huge = vec![3, 0, 0, 0, 1, 0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        // One byte over the limit, with a 3 byte length prefix
+        let mut over = vec![3, 0, 0, 0, 1, 0x83];
+        over.write_uint::<BigEndian>((MAX_PACKET_SIZE - 8) as u64, 3).unwrap();
+        let mut at_limit = vec![3, 0, 0, 0, 1, 0x83];
+        at_limit.write_uint::<BigEndian>((MAX_PACKET_SIZE - 9) as u64, 3).unwrap();
+        (vec![huge, over], at_limit)
+    }
+
+    #[test]
+    fn rejects_packets_over_max_size() {
+        let (oversized, at_limit) = oversized_headers();
+        for bytes in &oversized {
+            match BtpPacket::read_from(&mut Cursor::new(&bytes[..])) {
+                Err(Error::Invalid(_)) => {},
+                other => panic!("expected invalid packet error, got {:?}", other),
+            }
+        }
+        assert_eq!(packet_length(&at_limit).unwrap(), Some(MAX_PACKET_SIZE));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn codec_rejects_packets_over_max_size() {
+        let (oversized, at_limit) = oversized_headers();
+        for bytes in &oversized {
+            match BtpCodec.decode(&mut BytesMut::from(&bytes[..])) {
+                Err(Error::Invalid(_)) => {},
+                other => panic!("expected invalid packet error, got {:?}", other),
+            }
+        }
+        assert_eq!(BtpCodec.decode(&mut BytesMut::from(&at_limit[..])).unwrap(), None);
+    }
+
+    #[test]
+    fn read_from_fails_on_truncated_stream() {
+        let bytes = get_bytes1();
+        match BtpPacket::read_from(&mut Cursor::new(&bytes[..bytes.len() - 1])) {
+            Err(Error::Io(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {},
+            other => panic!("expected unexpected EOF, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_protocol_data() {
+        let bytes = get_bytes1();
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(BtpPacketRef::from_bytes(truncated).is_err());
+        assert!(BtpPacket::from_bytes(truncated).is_err());
+    }
+}
+
+#[cfg(test)]
+mod btp_error {
+    use super::*;
+
+    fn get_instance1() -> BtpPacket {
+        BtpPacket {
+            packet_type: PacketType::ErrorResponse,
+            request_id: 1,
+            data: PacketContents::ErrorResponse(ErrorResponse {
+                code: "L13".to_string(),
+                name: "errorName".to_string(),
+                triggered_at: DateTime::parse_from_rfc3339("2017-08-28T18:32:00.000Z").unwrap().with_timezone(&Utc),
+                data: "boo".to_string(),
+                protocol_data: vec![
+                    ProtocolData {
+                        protocol_name: String::from("ilp"),
+                        content_type: ContentType::ApplicationOctetStream,
+                        data: vec![1,28,0,0,0,0,0,0,0,100,17,101,120,97,109,112,108,101,46,114,101,100,46,97,108,105,99,101,0,0]
+                    },
+                    ProtocolData {
+                        protocol_name: "foo".to_string(),
+                        content_type: ContentType::ApplicationOctetStream,
+                        data: b"bar".to_vec()
+                    },
+                    ProtocolData {
+                        protocol_name: "beep".to_string(),
+                        content_type: ContentType::TextPlainUtf8,
+                        data: b"boop".to_vec()
+                    },
+                    ProtocolData {
+                        protocol_name: "json".to_string(),
+                        content_type: ContentType::ApplicationJson,
+                        data: b"{}".to_vec()
+                    }
+                ],
+            })
+        }
+    }
+
+    fn get_bytes1() -> Vec<u8> {
+        vec![2, 0, 0, 0, 1, 104, 76, 49, 51, 9, 101, 114, 114, 111, 114, 78, 97, 109, 101, 19, 50, 48, 49, 55, 48, 56, 50, 56, 49, 56, 51, 50, 48, 48, 46, 48, 48, 48, 90, 3, 98, 111, 111, 1, 4,3, 105, 108, 112, 0, 30, 1, 28, 0, 0, 0, 0, 0, 0, 0, 100, 17, 101, 120, 97, 109, 112, 108, 101, 46, 114, 101, 100, 46, 97, 108, 105, 99, 101, 0, 0, 3, 102, 111, 111, 0, 3, 98, 97, 114, 4, 98, 101, 101, 112,1, 4, 98, 111, 111, 112, 4, 106, 115, 111, 110, 2, 2, 123, 125]
+    }
+
+    #[test]
+    fn serialize() {
+        assert_eq!(get_instance1().to_bytes().unwrap(), get_bytes1());
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(BtpPacket::from_bytes(&get_bytes1()).unwrap(), get_instance1());
+    }
+
+    #[test]
+    fn deserialize_strict() {
+        assert_eq!(BtpPacket::from_bytes_strict(&get_bytes1()).unwrap(), get_instance1());
+    }
+
+    #[test]
+    fn strict_rejects_trailing_bytes() {
+        let mut bytes = get_bytes1();
+        bytes.push(0);
+        assert!(BtpPacket::from_bytes(&bytes).is_ok());
+        match BtpPacket::from_bytes_strict(&bytes) {
+            Err(Error::TrailingBytes(110)) => {},
+            other => panic!("expected trailing bytes at 110, got {:?}", other),
+        }
+
+        // Inside the contents, after the protocol data
+        bytes[5] += 1;
+        assert!(BtpPacket::from_bytes(&bytes).is_ok());
+        match BtpPacket::from_bytes_strict(&bytes) {
+            Err(Error::TrailingBytes(110)) => {},
+            other => panic!("expected trailing bytes at 110, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn strict_rejects_non_ascii_name() {
+        let mut bytes = get_bytes1();
+        bytes[11] = 0x7f + 1;
+        match BtpPacket::from_bytes_strict(&bytes) {
+            Err(Error::NotAscii("name", 11)) => {},
+            other => panic!("expected non-ASCII name at 11, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn strict_rejects_unknown_content_type() {
+        let mut bytes = get_bytes1();
+        bytes[49] = 9;
+        assert!(BtpPacket::from_bytes(&bytes).is_ok());
+        match BtpPacket::from_bytes_strict(&bytes) {
+            Err(Error::UnknownContentType(9, 49)) => {},
+            other => panic!("expected unknown content type at 49, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn strict_reports_where_truncated_packets_end() {
+        let bytes = get_bytes1();
+        match BtpPacket::from_bytes_strict(&bytes[..3]) {
+            Err(Error::Truncated("request_id", 1)) => {},
+            other => panic!("expected truncated request_id at 1, got {:?}", other),
+        }
+        match BtpPacket::from_bytes_strict(&bytes[..50]) {
+            Err(Error::Truncated("contents", 5)) => {},
+            other => panic!("expected truncated contents at 5, got {:?}", other),
+        }
+
+        // The contents length agrees with the packet, but the contents stop inside the data field
+        let mut bytes = bytes[..41].to_vec();
+        bytes[5] = 35;
+        assert!(BtpPacket::from_bytes(&bytes).is_err());
+        match BtpPacket::from_bytes_strict(&bytes) {
+            Err(Error::Truncated("data", 39)) => {},
+            other => panic!("expected truncated data at 39, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn strict_reports_where_invalid_fields_start() {
+        let mut bytes = get_bytes1();
+        bytes[20] = b'x';
+        match BtpPacket::from_bytes_strict(&bytes) {
+            Err(Error::InvalidField("triggered_at", 19, _)) => {},
+            other => panic!("expected invalid triggered_at at 19, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod btp_fulfill {
+    use super::*;
+
+    fn get_instance1() -> BtpPacket {
+        BtpPacket {
+            packet_type: PacketType::Fulfill,
+            request_id: 1,
+            data: PacketContents::Fulfill(Fulfill {
+                transfer_id: [180,200,56,246,128,177,71,248,168,46,177,252,251,237,137,213],
+                fulfillment: [219, 42, 249, 249, 219, 166, 255, 52, 179, 237, 173, 251, 152, 107, 155, 180, 205, 75, 75, 65, 229, 4, 65, 25, 197, 93, 52, 175, 218, 191, 252, 2],
+                protocol_data: vec![
+                    ProtocolData {
+                        protocol_name: String::from("ilp"),
+                        content_type: ContentType::ApplicationOctetStream,
+                        data: vec![1,28,0,0,0,0,0,0,0,100,17,101,120,97,109,112,108,101,46,114,101,100,46,97,108,105,99,101,0,0]
+                    },
+                    ProtocolData {
+                        protocol_name: "foo".to_string(),
+                        content_type: ContentType::ApplicationOctetStream,
+                        data: b"bar".to_vec()
+                    },
+                    ProtocolData {
+                        protocol_name: "beep".to_string(),
+                        content_type: ContentType::TextPlainUtf8,
+                        data: b"boop".to_vec()
+                    },
+                    ProtocolData {
+                        protocol_name: "json".to_string(),
+                        content_type: ContentType::ApplicationJson,
+                        data: b"{}".to_vec()
+                    }
+                ],
+            })
+        }
+    }
+
+    fn get_bytes1() -> Vec<u8> {
+        vec![4, 0, 0, 0, 1, 115, 180, 200, 56, 246, 128, 177, 71, 248, 168, 46, 177, 252, 251, 237, 137, 213, 219, 42, 249, 249, 219, 166, 255, 52, 179, 237, 173, 251, 152, 107, 155, 180, 205, 75, 75, 65, 229, 4, 65, 25, 197, 93, 52, 175, 218, 191, 252, 2, 1, 4, 3, 105, 108, 112, 0, 30, 1, 28, 0, 0, 0, 0, 0, 0, 0, 100, 17, 101,120, 97, 109, 112, 108, 101, 46, 114, 101, 100, 46, 97, 108, 105, 99, 101, 0, 0, 3, 102, 111, 111, 0, 3, 98, 97, 114, 4, 98, 101, 101, 112, 1, 4, 98, 111, 111, 112, 4, 106, 115, 111, 110, 2, 2, 123, 125]
+    }
+
+    #[test]
+    fn serialize() {
+        assert_eq!(get_instance1().to_bytes().unwrap(), get_bytes1());
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(BtpPacket::from_bytes(&get_bytes1()).unwrap(), get_instance1());
+    }
+
+    #[test]
+    fn round_trips_unknown_content_type() {
+        let mut bytes = get_bytes1();
+        // Content type of the "foo" protocol data
+        bytes[96] = 7;
+        let packet = BtpPacket::from_bytes(&bytes).unwrap();
+        match packet.data {
+            PacketContents::Fulfill(ref fulfill) => assert_eq!(fulfill.protocol_data[1].content_type, ContentType::Unknown(7)),
+            ref other => panic!("expected fulfill, got {:?}", other),
+        }
+        assert_eq!(packet.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn passes_through_unknown_packet_types() {
+        let mut bytes = get_bytes1();
+        bytes[0] = 42;
+        let packet = BtpPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(packet.packet_type, PacketType::Unknown(42));
+        assert_eq!(packet.data, PacketContents::Unknown(bytes[6..].to_vec()));
+        assert_eq!(packet.to_bytes().unwrap(), bytes);
+    }
+}
+
+#[cfg(test)]
+mod typed_protocol_data {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Quote {
+        amount: u64,
+    }
+
+    #[test]
+    fn json() {
+        let protocol_data = ProtocolData::json("quote", &Quote { amount: 10 }).unwrap();
+        assert_eq!(protocol_data.content_type, ContentType::ApplicationJson);
+        assert_eq!(protocol_data.data, br#"{"amount":10}"#.to_vec());
+        assert_eq!(protocol_data.as_json::<Quote>().unwrap(), Quote { amount: 10 });
+        match protocol_data.as_text() {
+            Err(Error::WrongContentType(ContentType::TextPlainUtf8, ContentType::ApplicationJson)) => {},
+            other => panic!("expected wrong content type, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn text() {
+        let protocol_data = ProtocolData::text("memo", "hello");
+        assert_eq!(protocol_data.as_text().unwrap(), "hello");
+        assert!(protocol_data.as_json::<Quote>().is_err());
+    }
+
+    #[test]
+    fn finds_protocols() {
+        let protocol_data = vec![ProtocolData::text("memo", "hi"), ProtocolData::ilp(vec![1, 2, 3])];
+        assert_eq!(find_protocol(&protocol_data, "ilp").unwrap().data, vec![1, 2, 3]);
+        assert!(find_protocol(&protocol_data, "foo").is_none());
+    }
+}
+
+#[cfg(test)]
+mod round_trip {
+    use super::*;
+    use chrono::TimeZone;
+    use proptest::prelude::*;
+    use proptest::collection::vec;
+
+    prop_compose! {
+        fn arb_datetime()(secs in 0i64..253_402_300_800, millis in 0u32..1000) -> DateTime<Utc> {
+            Utc.timestamp(secs, millis * 1_000_000)
+        }
+    }
+
+    fn arb_content_type() -> BoxedStrategy<ContentType> {
+        prop_oneof![
+            Just(ContentType::ApplicationOctetStream),
+            Just(ContentType::TextPlainUtf8),
+            Just(ContentType::ApplicationJson),
+            (3u8..255).prop_map(ContentType::Unknown),
+        ].boxed()
+    }
+
+    prop_compose! {
+        // Long enough data to need multi-byte length prefixes
+        fn arb_protocol_data()(protocol_name in "[a-z_]{1,20}", content_type in arb_content_type(), data in vec(any::<u8>(), 0..300)) -> ProtocolData {
+            ProtocolData {
+                protocol_name,
+                content_type,
+                data,
+            }
+        }
+    }
+
+    fn arb_protocol_data_list() -> BoxedStrategy<Vec<ProtocolData>> {
+        vec(arb_protocol_data(), 0..5).boxed()
+    }
+
+    prop_compose! {
+        fn arb_response()(protocol_data in arb_protocol_data_list()) -> Response {
+            Response { protocol_data }
+        }
+    }
+
+    prop_compose! {
+        fn arb_error_response()(code in "[A-Z][0-9]{2}", name in "[ -~]{0,20}", triggered_at in arb_datetime(), data in ".{0,20}", protocol_data in arb_protocol_data_list()) -> ErrorResponse {
+            ErrorResponse { code, name, triggered_at, data, protocol_data }
+        }
+    }
+
+    prop_compose! {
+        fn arb_prepare()(transfer_id in any::<[u8; 16]>(), amount in any::<u64>(), execution_condition in any::<[u8; 32]>(), expires_at in arb_datetime(), protocol_data in arb_protocol_data_list()) -> Prepare {
+            Prepare { transfer_id, amount, execution_condition, expires_at, protocol_data }
+        }
+    }
+
+    prop_compose! {
+        fn arb_fulfill()(transfer_id in any::<[u8; 16]>(), fulfillment in any::<[u8; 32]>(), protocol_data in arb_protocol_data_list()) -> Fulfill {
+            Fulfill { transfer_id, fulfillment, protocol_data }
+        }
+    }
+
+    prop_compose! {
+        fn arb_reject()(transfer_id in any::<[u8; 16]>(), protocol_data in arb_protocol_data_list()) -> Reject {
+            Reject { transfer_id, protocol_data }
+        }
+    }
+
+    prop_compose! {
+        fn arb_message()(protocol_data in arb_protocol_data_list()) -> Message {
+            Message { protocol_data }
+        }
+    }
+
+    fn arb_contents() -> BoxedStrategy<(PacketType, PacketContents)> {
+        prop_oneof![
+            arb_response().prop_map(|c| (PacketType::Response, PacketContents::Response(c))),
+            arb_error_response().prop_map(|c| (PacketType::ErrorResponse, PacketContents::ErrorResponse(c))),
+            arb_prepare().prop_map(|c| (PacketType::Prepare, PacketContents::Prepare(c))),
+            arb_fulfill().prop_map(|c| (PacketType::Fulfill, PacketContents::Fulfill(c))),
+            arb_reject().prop_map(|c| (PacketType::Reject, PacketContents::Reject(c))),
+            arb_message().prop_map(|c| (PacketType::Message, PacketContents::Message(c))),
+            (7u8..255, vec(any::<u8>(), 0..300)).prop_map(|(t, c)| (PacketType::Unknown(t), PacketContents::Unknown(c))),
+        ].boxed()
+    }
+
+    prop_compose! {
+        fn arb_packet()(request_id in any::<u32>(), (packet_type, data) in arb_contents()) -> BtpPacket {
+            BtpPacket { packet_type, request_id, data }
+        }
+    }
+
+    #[test]
+    fn more_than_255_protocol_data_entries() {
+        let message = BtpPacket {
+            packet_type: PacketType::Message,
+            request_id: 1,
+            data: PacketContents::Message(Message {
+                protocol_data: (0..300).map(|i| ProtocolData {
+                    protocol_name: format!("p{}", i),
+                    content_type: ContentType::ApplicationOctetStream,
+                    data: Vec::new(),
+                }).collect(),
+            }),
+        };
+        let bytes = message.to_bytes().unwrap();
+        // Type, request id, two byte length-of-length prefix, then the count
+        assert_eq!(&bytes[8..11], &[2, 1, 44]);
+        assert_eq!(BtpPacket::from_bytes_strict(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn var_uint_uses_minimal_bytes() {
+        for &(value, ref expected) in &[(0u64, vec![1u8, 0]), (255, vec![1, 255]), (256, vec![2, 1, 0]), (u64::max_value(), vec![8, 255, 255, 255, 255, 255, 255, 255, 255])] {
+            let mut bytes = Vec::new();
+            write_var_uint(&mut bytes, value).unwrap();
+            assert_eq!(&bytes, expected);
+            assert_eq!(read_var_uint(&mut Cursor::new(&bytes[..])).unwrap(), value);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn decode_is_inverse_of_encode(ref packet in arb_packet()) {
+            let bytes = packet.to_bytes().unwrap();
+            assert_eq!(&BtpPacket::from_bytes(&bytes).unwrap(), packet);
+            assert_eq!(&BtpPacketRef::from_bytes(&bytes).unwrap().to_owned(), packet);
+        }
+
+        #[test]
+        fn never_panics_on_arbitrary_bytes(ref bytes in vec(any::<u8>(), 0..500)) {
+            let _ = BtpPacket::from_bytes(&bytes);
+            let _ = BtpPacket::from_bytes_strict(&bytes);
+            let _ = v2::BtpPacket::from_bytes(&bytes);
+        }
+
+        #[test]
+        fn never_panics_on_mutated_packets(ref packet in arb_packet(), index in any::<usize>(), byte in any::<u8>()) {
+            let mut bytes = packet.to_bytes().unwrap();
+            let index = index % bytes.len();
+            bytes[index] = byte;
+            let _ = BtpPacket::from_bytes(&bytes);
+            let _ = BtpPacket::from_bytes_strict(&bytes);
+        }
+    }
+}
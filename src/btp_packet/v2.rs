@@ -0,0 +1,205 @@
+use std::io::Cursor;
+use ilp_packet::oer::WriteOerExt;
+use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use super::{Serializable, Error, ProtocolData, DecodeMode, DecodeContext, ResponseRef, ErrorResponseRef, MessageRef};
+use super::{read_var_octet_slice, read_protocol_data_ref, protocol_data_to_owned, write_protocol_data};
+// These are encoded the same way in both versions
+pub use super::{ContentType, Response, ErrorResponse, Message};
+
+// BTP 2.0 has no conditional transfers of its own. ILP packets ride in the protocol data of
+// Messages and Responses, and Transfers only move money to settle
+#[derive(Debug, PartialEq, Clone)]
+pub enum PacketType {
+    Response,
+    Error,
+    Message,
+    Transfer,
+    Unknown(u8),
+}
+
+impl From<u8> for PacketType {
+    fn from(type_int: u8) -> Self {
+        match type_int {
+            1 => PacketType::Response,
+            2 => PacketType::Error,
+            6 => PacketType::Message,
+            7 => PacketType::Transfer,
+            _ => PacketType::Unknown(type_int),
+        }
+    }
+}
+
+impl From<PacketType> for u8 {
+    fn from(packet_type: PacketType) -> Self {
+        match packet_type {
+            PacketType::Response => 1,
+            PacketType::Error => 2,
+            PacketType::Message => 6,
+            PacketType::Transfer => 7,
+            PacketType::Unknown(type_int) => type_int,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PacketContents {
+    Response(Response),
+    Error(ErrorResponse),
+    Message(Message),
+    Transfer(Transfer),
+    Unknown(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BtpPacket {
+    pub packet_type: PacketType,
+    pub request_id: u32,
+    pub data: PacketContents,
+}
+
+impl BtpPacket {
+    pub fn from_bytes_strict(bytes: &[u8]) -> Result<BtpPacket, Error> {
+        BtpPacket::from_bytes_with_mode(bytes, DecodeMode::Strict)
+    }
+
+    pub fn from_bytes_with_mode(bytes: &[u8], mode: DecodeMode) -> Result<BtpPacket, Error> {
+        let context = DecodeContext::new(mode);
+        let mut reader = Cursor::new(bytes);
+        let packet_type = PacketType::from(context.read("packet_type", &mut reader, |reader| reader.read_u8())?);
+        let request_id = context.read("request_id", &mut reader, |reader| reader.read_u32::<BigEndian>())?;
+        let content_bytes = context.read("contents", &mut reader, read_var_octet_slice)?;
+        context.check_end(&reader)?;
+        let content_context = DecodeContext {
+            mode,
+            offset: context.field_position(&reader, content_bytes),
+        };
+        let data = match packet_type {
+            PacketType::Response => PacketContents::Response(ResponseRef::decode(content_bytes, content_context)?.to_owned()),
+            PacketType::Error => PacketContents::Error(ErrorResponseRef::decode(content_bytes, content_context)?.to_owned()),
+            PacketType::Message => PacketContents::Message(MessageRef::decode(content_bytes, content_context)?.to_owned()),
+            PacketType::Transfer => PacketContents::Transfer(Transfer::decode(content_bytes, content_context)?),
+            PacketType::Unknown(_) => PacketContents::Unknown(content_bytes.to_vec()),
+        };
+        Ok(BtpPacket {
+            packet_type,
+            request_id,
+            data,
+        })
+    }
+}
+
+impl Serializable<BtpPacket> for BtpPacket {
+    fn from_bytes(bytes: &[u8]) -> Result<BtpPacket, Error> {
+        BtpPacket::from_bytes_with_mode(bytes, DecodeMode::Lenient)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_u8(u8::from(self.packet_type.clone()))?;
+        bytes.write_u32::<BigEndian>(self.request_id)?;
+        let content_bytes: Vec<u8> = match self.data {
+            PacketContents::Response(ref contents) => contents.to_bytes()?,
+            PacketContents::Error(ref contents) => contents.to_bytes()?,
+            PacketContents::Message(ref contents) => contents.to_bytes()?,
+            PacketContents::Transfer(ref contents) => contents.to_bytes()?,
+            PacketContents::Unknown(ref contents) => contents.to_vec(),
+        };
+        bytes.write_var_octet_string(&content_bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Transfer {
+    pub amount: u64,
+    pub protocol_data: Vec<ProtocolData>,
+}
+
+impl Transfer {
+    fn decode(bytes: &[u8], context: DecodeContext) -> Result<Transfer, Error> {
+        let mut reader = Cursor::new(bytes);
+        let amount = context.read("amount", &mut reader, |reader| reader.read_u64::<BigEndian>())?;
+        let protocol_data = read_protocol_data_ref(&mut reader, context)?;
+        Ok(Transfer {
+            amount,
+            protocol_data: protocol_data_to_owned(&protocol_data),
+        })
+    }
+}
+
+impl Serializable<Transfer> for Transfer {
+    fn from_bytes(bytes: &[u8]) -> Result<Transfer, Error> {
+        Transfer::decode(bytes, DecodeContext::new(DecodeMode::Lenient))
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_u64::<BigEndian>(self.amount)?;
+        write_protocol_data(&mut bytes, &self.protocol_data)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod btp_transfer {
+    use super::*;
+    use btp_packet::{BtpPacket as BtpPacketV1, PacketContents as PacketContentsV1};
+
+    fn get_instance1() -> BtpPacket {
+        BtpPacket {
+            packet_type: PacketType::Transfer,
+            request_id: 1,
+            data: PacketContents::Transfer(Transfer {
+                amount: 1000,
+                protocol_data: vec![
+                    ProtocolData {
+                        protocol_name: "foo".to_string(),
+                        content_type: ContentType::ApplicationOctetStream,
+                        data: b"bar".to_vec()
+                    },
+                ],
+            }),
+        }
+    }
+
+    fn get_bytes1() -> Vec<u8> {
+        vec![7, 0, 0, 0, 1, 19, 0, 0, 0, 0, 0, 0, 3, 232, 1, 1, 3, 102, 111, 111, 0, 3, 98, 97, 114]
+    }
+
+    #[test]
+    fn serialize() {
+        assert_eq!(get_instance1().to_bytes().unwrap(), get_bytes1());
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(BtpPacket::from_bytes(&get_bytes1()).unwrap(), get_instance1());
+        assert_eq!(BtpPacket::from_bytes_strict(&get_bytes1()).unwrap(), get_instance1());
+    }
+
+    #[test]
+    fn strict_reports_where_truncated_packets_end() {
+        let mut bytes = get_bytes1()[..10].to_vec();
+        bytes[5] = 4;
+        match BtpPacket::from_bytes_strict(&bytes) {
+            Err(Error::Truncated("amount", 6)) => {},
+            other => panic!("expected truncated amount at 6, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn shares_message_format_with_v1() {
+        let message = BtpPacket {
+            packet_type: PacketType::Message,
+            request_id: 2,
+            data: PacketContents::Message(Message {
+                protocol_data: Vec::new(),
+            }),
+        };
+        let bytes = message.to_bytes().unwrap();
+        match BtpPacketV1::from_bytes(&bytes).unwrap().data {
+            PacketContentsV1::Message(ref message) => assert!(message.protocol_data.is_empty()),
+            ref other => panic!("expected message, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::cmp;
+use uuid::Uuid;
+use chrono::{Utc, Duration};
+use plugin;
+use plugin::{IlpPlugin, Transfer, TransferResult, find_response_data};
+use ilp_error::IlpError;
+use psk2;
+use psk2::{PskRequest, PskPacketType};
+
+const DEFAULT_MAX_CONSECUTIVE_FAILURES: u32 = 5;
+// Seconds each chunk's transfer is held for
+const CHUNK_HOLD_DURATION: i64 = 30;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Plugin(err: plugin::Error) {
+            description(err.description())
+            from()
+        }
+        Psk2(err: psk2::Error) {
+            description(err.description())
+            from()
+        }
+        Rejected(error: IlpError, progress: PaymentProgress) {
+            description("chunk was rejected with a final error")
+        }
+        TooManyFailures(progress: PaymentProgress) {
+            description("too many chunks in a row failed")
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PaymentProgress {
+    pub source_amount_sent: u64,
+    // As reported by the receiver
+    pub destination_amount_delivered: u64,
+    pub chunks_fulfilled: u32,
+}
+
+// Sends a payment as many PSK2 chunks, each small enough for the connectors on the path
+pub struct ChunkedSender {
+    pub destination_account: String,
+    pub shared_secret: Vec<u8>,
+    // Minimum destination units the receiver should get per source unit sent
+    pub min_exchange_rate: f64,
+    // The chunk size shrinks whenever a connector says a chunk is too large
+    pub initial_chunk_size: u64,
+    pub max_consecutive_failures: u32,
+}
+
+impl ChunkedSender {
+    // There is no default rate, since with none the connectors on the path could keep the money
+    pub fn new(destination_account: &str, shared_secret: &[u8], min_exchange_rate: f64) -> Self {
+        ChunkedSender {
+            destination_account: destination_account.to_string(),
+            shared_secret: shared_secret.to_vec(),
+            min_exchange_rate,
+            initial_chunk_size: u64::max_value(),
+            max_consecutive_failures: DEFAULT_MAX_CONSECUTIVE_FAILURES,
+        }
+    }
+
+    // Calls on_progress after every fulfilled chunk
+    pub fn send<P, F>(&self, plugin: &mut P, source_amount: u64, mut on_progress: F) -> Result<PaymentProgress, Error>
+        where P: IlpPlugin, F: FnMut(&PaymentProgress)
+    {
+        let payment_id = *Uuid::new_v4().as_bytes();
+        let mut progress = PaymentProgress::default();
+        let mut chunk_size = cmp::max(self.initial_chunk_size, 1);
+        let mut sequence = 0;
+        let mut consecutive_failures = 0;
+
+        while progress.source_amount_sent < source_amount {
+            let remaining = source_amount - progress.source_amount_sent;
+            let amount = cmp::min(chunk_size, remaining);
+            let request = PskRequest {
+                payment_id,
+                sequence,
+                chunk_amount: (amount as f64 * self.min_exchange_rate).floor() as u64,
+                last_chunk: amount == remaining,
+                data: Vec::new(),
+            };
+            let (packet, condition) = psk2::create_request(&self.shared_secret, &self.destination_account, &request)?;
+            let transfer = Transfer {
+                id: *Uuid::new_v4().as_bytes(),
+                amount,
+                ilp: packet,
+                execution_condition: condition,
+                expires_at: Utc::now().checked_add_signed(Duration::seconds(CHUNK_HOLD_DURATION)).unwrap().to_rfc3339(),
+            };
+
+            match plugin.send_transfer(transfer)? {
+                TransferResult::Fulfilled { protocol_data, .. } => {
+                    consecutive_failures = 0;
+                    sequence += 1;
+                    progress.source_amount_sent += amount;
+                    progress.chunks_fulfilled += 1;
+                    let response = find_response_data(&protocol_data, psk2::PROTOCOL_NAME)
+                        .and_then(|p| psk2::parse_response(&self.shared_secret, &p.data).ok());
+                    if let Some(response) = response {
+                        if response.packet_type == PskPacketType::Response && response.payment_id == payment_id {
+                            progress.destination_amount_delivered = response.amount_received;
+                        }
+                    }
+                    on_progress(&progress);
+                },
+                TransferResult::Rejected { ilp_error, .. } => {
+                    consecutive_failures += 1;
+                    if consecutive_failures >= self.max_consecutive_failures {
+                        return Err(Error::TooManyFailures(progress));
+                    }
+                    match ilp_error {
+                        Some(ref error) if error.code == "F08" => {
+                            // Scale the chunk down to the maximum the connector will forward,
+                            // or just halve it if the connector didn't say what that is
+                            chunk_size = match error.amount_too_large() {
+                                Some((received_amount, maximum_amount)) if received_amount > 0 => {
+                                    (amount as f64 * maximum_amount as f64 / received_amount as f64).floor() as u64
+                                },
+                                _ => amount / 2,
+                            };
+                            chunk_size = cmp::max(chunk_size, 1);
+                        },
+                        Some(ref error) if !error.is_temporary() => {
+                            return Err(Error::Rejected(error.clone(), progress));
+                        },
+                        // Temporary errors are retried with the same chunk size
+                        _ => {},
+                    }
+                },
+            }
+        }
+
+        Ok(progress)
+    }
+}
+
+#[cfg(test)]
+mod sender {
+    use super::*;
+    use std::thread;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use websocket::OwnedMessage;
+    use websocket::sync::Server as WsServer;
+    use btp_packet::{BtpPacket, PacketType, PacketContents, Fulfill, Reject, Response, ProtocolData, Serializable};
+    use psk2::{PaymentTracker, ChunkResult};
+    use plugin::{Plugin, MemoryPlugin};
+
+    const SHARED_SECRET: [u8; 32] = [3; 32];
+    const MAX_PACKET_AMOUNT: u64 = 30;
+
+    fn protocol_data(name: &str, data: Vec<u8>) -> Vec<ProtocolData> {
+        vec![ProtocolData::octet_stream(name, data)]
+    }
+
+    // Stands in for a connector that caps packet amounts in front of a PSK2 receiver
+    fn spawn_connector() -> String {
+        let mut server = WsServer::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut tracker = PaymentTracker::new();
+            loop {
+                let mut connection = match server.accept() {
+                    Ok(upgrade) => upgrade.accept().unwrap(),
+                    Err(_) => continue,
+                };
+                // Accept whatever credentials come in the auth message
+                let auth_id = match connection.recv_message().unwrap() {
+                    OwnedMessage::Binary(bytes) => BtpPacket::from_bytes(&bytes).unwrap().request_id,
+                    _ => continue,
+                };
+                let auth_response = BtpPacket {
+                    packet_type: PacketType::Response,
+                    request_id: auth_id,
+                    data: PacketContents::Response(Response {
+                        protocol_data: Vec::new(),
+                    }),
+                };
+                connection.send_message(&OwnedMessage::Binary(auth_response.to_bytes().unwrap())).unwrap();
+                let prepare = match connection.recv_message().unwrap() {
+                    OwnedMessage::Binary(bytes) => match BtpPacket::from_bytes(&bytes).unwrap().data {
+                        PacketContents::Prepare(prepare) => prepare,
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+
+                let (packet_type, data) = if prepare.amount > MAX_PACKET_AMOUNT {
+                    let mut amounts = Vec::new();
+                    amounts.write_u64::<BigEndian>(prepare.amount).unwrap();
+                    amounts.write_u64::<BigEndian>(MAX_PACKET_AMOUNT).unwrap();
+                    let error = IlpError {
+                        code: "F08".to_string(),
+                        name: "Amount Too Large".to_string(),
+                        triggered_by: "example.connie".to_string(),
+                        forwarded_by: Vec::new(),
+                        triggered_at: Utc::now(),
+                        data: amounts,
+                    };
+                    (PacketType::Reject, PacketContents::Reject(Reject {
+                        transfer_id: prepare.transfer_id,
+                        protocol_data: protocol_data("ilp", error.to_bytes().unwrap()),
+                    }))
+                } else {
+                    match tracker.handle_chunk(&SHARED_SECRET, prepare.amount, &prepare.protocol_data[0].data).unwrap() {
+                        ChunkResult::Fulfill { fulfillment, response } => (PacketType::Fulfill, PacketContents::Fulfill(Fulfill {
+                            transfer_id: prepare.transfer_id,
+                            fulfillment,
+                            protocol_data: protocol_data(psk2::PROTOCOL_NAME, response),
+                        })),
+                        ChunkResult::Reject { response } => (PacketType::Reject, PacketContents::Reject(Reject {
+                            transfer_id: prepare.transfer_id,
+                            protocol_data: protocol_data(psk2::PROTOCOL_NAME, response),
+                        })),
+                    }
+                };
+                let reply = BtpPacket {
+                    packet_type,
+                    request_id: 2,
+                    data,
+                };
+                connection.send_message(&OwnedMessage::Binary(reply.to_bytes().unwrap())).unwrap();
+            }
+        });
+        format!("btp+ws://{}", address)
+    }
+
+    #[test]
+    fn shrinks_chunks_to_connector_maximum() {
+        let mut plugin = Plugin::new(&spawn_connector()).unwrap();
+        let sender = ChunkedSender::new("example.bob", &SHARED_SECRET, 1.0);
+
+        let mut updates = Vec::new();
+        let progress = sender.send(&mut plugin, 100, |progress| updates.push(progress.clone())).unwrap();
+        assert_eq!(progress, PaymentProgress {
+            source_amount_sent: 100,
+            destination_amount_delivered: 100,
+            chunks_fulfilled: 4,
+        });
+        let delivered: Vec<u64> = updates.iter().map(|p| p.destination_amount_delivered).collect();
+        assert_eq!(delivered, vec![30, 60, 90, 100]);
+    }
+
+    #[test]
+    fn enforces_min_exchange_rate() {
+        let (mut sender_plugin, receiver_plugin) = MemoryPlugin::pair();
+        let mut tracker = PaymentTracker::new();
+        receiver_plugin.set_incoming_handler(move |transfer| {
+            match tracker.handle_chunk(&SHARED_SECRET, transfer.amount, &transfer.ilp).unwrap() {
+                ChunkResult::Fulfill { fulfillment, response } => TransferResult::Fulfilled {
+                    fulfillment,
+                    protocol_data: protocol_data(psk2::PROTOCOL_NAME, response),
+                },
+                ChunkResult::Reject { response } => TransferResult::Rejected {
+                    ilp_error: None,
+                    protocol_data: protocol_data(psk2::PROTOCOL_NAME, response),
+                },
+            }
+        });
+
+        let progress = ChunkedSender::new("example.bob", &SHARED_SECRET, 1.0).send(&mut sender_plugin, 10, |_| {}).unwrap();
+        assert_eq!(progress.destination_amount_delivered, 10);
+
+        // The memory plugin delivers exactly what was sent, which is less than the rate asks for
+        match ChunkedSender::new("example.bob", &SHARED_SECRET, 2.0).send(&mut sender_plugin, 10, |_| {}) {
+            Err(Error::TooManyFailures(ref progress)) => assert_eq!(progress.source_amount_sent, 0),
+            other => panic!("expected TooManyFailures, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+// Human-readable renderings of BTP and ILP packets, for debugging connector integrations
+use std::fmt;
+use std::str;
+use std::io::Cursor;
+use byteorder::{BigEndian, ReadBytesExt};
+use serde_json;
+use serde_json::Value;
+use base64;
+use btp_packet::{BtpPacket, PacketContents, ProtocolData, ContentType, Error as BtpError, read_var_octet_slice};
+use ilp_error::IlpError;
+use ilpv4::{IlpPacket, read_ilp_payment};
+
+const ILP_PAYMENT_TYPE: u8 = 1;
+const ILQP_BY_SOURCE_REQUEST_TYPE: u8 = 4;
+const ILQP_BY_DESTINATION_REQUEST_TYPE: u8 = 6;
+const ILP_ERROR_TYPE: u8 = 8;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Hex(descr: &'static str) {
+            description(descr)
+        }
+        Base64(err: base64::DecodeError) {
+            description(err.description())
+            from()
+        }
+        UnknownPacket {
+            description("input is not a BTP, ILP or ILQP packet")
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Hex,
+    Base64,
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(string: &str) -> Result<Vec<u8>, Error> {
+    let string = string.trim();
+    let string = if string.starts_with("0x") { &string[2..] } else { string };
+    if string.len() % 2 != 0 {
+        return Err(Error::Hex("hex input must have an even number of digits"));
+    }
+    string.as_bytes().chunks(2)
+        .map(|pair| {
+            str::from_utf8(pair).ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(Error::Hex("input is not valid hex"))
+        })
+        .collect()
+}
+
+// Decodes the input as a BTP packet if it is one, otherwise as an ILP or ILQP packet
+pub fn decode(input: &str, encoding: Encoding) -> Result<Value, Error> {
+    let bytes = match encoding {
+        Encoding::Hex => from_hex(input)?,
+        Encoding::Base64 => base64::decode(input.trim())?,
+    };
+    match BtpPacket::from_bytes_strict(&bytes) {
+        Ok(BtpPacket { data: PacketContents::Unknown(_), .. }) | Err(_) => {},
+        Ok(packet) => return Ok(packet.to_json()),
+    }
+    ilp_to_json(&bytes).ok_or(Error::UnknownPacket)
+}
+
+impl BtpPacket {
+    pub fn to_json(&self) -> Value {
+        let (packet_type, contents) = match self.data {
+            PacketContents::Response(ref response) => ("Response", json!({
+                "protocol_data": protocol_data_to_json(&response.protocol_data),
+            })),
+            PacketContents::ErrorResponse(ref error) => ("Error", json!({
+                "code": error.code,
+                "name": error.name,
+                "triggered_at": error.triggered_at.to_rfc3339(),
+                "data": error.data,
+                "protocol_data": protocol_data_to_json(&error.protocol_data),
+            })),
+            PacketContents::Prepare(ref prepare) => ("Prepare", json!({
+                "transfer_id": to_hex(&prepare.transfer_id),
+                "amount": prepare.amount,
+                "execution_condition": to_hex(&prepare.execution_condition),
+                "expires_at": prepare.expires_at.to_rfc3339(),
+                "protocol_data": protocol_data_to_json(&prepare.protocol_data),
+            })),
+            PacketContents::Fulfill(ref fulfill) => ("Fulfill", json!({
+                "transfer_id": to_hex(&fulfill.transfer_id),
+                "fulfillment": to_hex(&fulfill.fulfillment),
+                "protocol_data": protocol_data_to_json(&fulfill.protocol_data),
+            })),
+            PacketContents::Reject(ref reject) => ("Reject", json!({
+                "transfer_id": to_hex(&reject.transfer_id),
+                "protocol_data": protocol_data_to_json(&reject.protocol_data),
+            })),
+            PacketContents::Message(ref message) => ("Message", json!({
+                "protocol_data": protocol_data_to_json(&message.protocol_data),
+            })),
+            PacketContents::Unknown(ref contents) => ("Unknown", json!({
+                "contents": to_hex(contents),
+            })),
+        };
+        json!({
+            "packet_type": packet_type,
+            "packet_type_id": u8::from(self.packet_type.clone()),
+            "request_id": self.request_id,
+            "contents": contents,
+        })
+    }
+}
+
+// Pretty-printed JSON, so packets can be logged with {}
+impl fmt::Display for BtpPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let json = serde_json::to_string_pretty(&self.to_json()).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+fn protocol_data_to_json(protocol_data: &[ProtocolData]) -> Value {
+    Value::Array(protocol_data.iter().map(|p| {
+        let data = if p.protocol_name == "ilp" {
+            ilp_to_json(&p.data).unwrap_or_else(|| Value::String(to_hex(&p.data)))
+        } else {
+            match p.content_type {
+                ContentType::TextPlainUtf8 => p.as_text().map(|text| Value::String(text.to_string())).ok(),
+                ContentType::ApplicationJson => p.as_json::<Value>().ok(),
+                _ => None,
+            }.unwrap_or_else(|| Value::String(to_hex(&p.data)))
+        };
+        json!({
+            "protocol_name": p.protocol_name,
+            "content_type": format!("{:?}", p.content_type),
+            "data": data,
+        })
+    }).collect())
+}
+
+// ILQP requests by source and by destination have the same layout, only the amount means
+// something different. ilp_packet's decoders panic on bad lengths so we read them ourselves
+fn read_ilqp_request(bytes: &[u8]) -> Result<(String, u64, u32), BtpError> {
+    let mut envelope = Cursor::new(bytes);
+    envelope.read_u8()?;
+    let mut reader = Cursor::new(read_var_octet_slice(&mut envelope)?);
+    let destination_account = str::from_utf8(read_var_octet_slice(&mut reader)?)?.to_string();
+    let amount = reader.read_u64::<BigEndian>()?;
+    let destination_hold_duration = reader.read_u32::<BigEndian>()?;
+    Ok((destination_account, amount, destination_hold_duration))
+}
+
+// Returns None if the bytes aren't an ILP or ILQP packet we know how to decode
+pub fn ilp_to_json(bytes: &[u8]) -> Option<Value> {
+    match bytes.first() {
+        Some(&ILP_PAYMENT_TYPE) => read_ilp_payment(bytes).ok().map(|payment| json!({
+            "packet_type": "IlpPayment",
+            "amount": payment.amount,
+            "account": payment.account,
+            "data": to_hex(&payment.data),
+        })),
+        Some(&ILQP_BY_SOURCE_REQUEST_TYPE) => read_ilqp_request(bytes).ok().map(|(account, amount, hold_duration)| json!({
+            "packet_type": "IlqpBySourceRequest",
+            "destination_account": account,
+            "source_amount": amount,
+            "destination_hold_duration": hold_duration,
+        })),
+        Some(&ILQP_BY_DESTINATION_REQUEST_TYPE) => read_ilqp_request(bytes).ok().map(|(account, amount, hold_duration)| json!({
+            "packet_type": "IlqpByDestinationRequest",
+            "destination_account": account,
+            "destination_amount": amount,
+            "destination_hold_duration": hold_duration,
+        })),
+        Some(&ILP_ERROR_TYPE) => IlpError::from_bytes(bytes).ok().map(|error| json!({
+            "packet_type": "IlpError",
+            "code": error.code,
+            "name": error.name,
+            "triggered_by": error.triggered_by,
+            "forwarded_by": error.forwarded_by,
+            "triggered_at": error.triggered_at.to_rfc3339(),
+            "data": to_hex(&error.data),
+        })),
+        Some(_) => IlpPacket::from_bytes(bytes).ok().map(|packet| match packet {
+            IlpPacket::Prepare(prepare) => json!({
+                "packet_type": "IlpPrepare",
+                "amount": prepare.amount,
+                "expires_at": prepare.expires_at.to_rfc3339(),
+                "execution_condition": to_hex(&prepare.execution_condition),
+                "destination": prepare.destination,
+                "data": to_hex(&prepare.data),
+            }),
+            IlpPacket::Fulfill(fulfill) => json!({
+                "packet_type": "IlpFulfill",
+                "fulfillment": to_hex(&fulfill.fulfillment),
+                "data": to_hex(&fulfill.data),
+            }),
+            IlpPacket::Reject(reject) => json!({
+                "packet_type": "IlpReject",
+                "code": reject.code,
+                "triggered_by": reject.triggered_by,
+                "message": reject.message,
+                "data": to_hex(&reject.data),
+            }),
+        }),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod decoding {
+    use super::*;
+    use chrono::{Utc, TimeZone};
+    use ilp_packet::packet::{IlpPayment, IlqpBySourceRequest};
+    use btp_packet::{PacketType, Prepare, Serializable};
+
+    #[test]
+    fn hex() {
+        assert_eq!(from_hex("0x00ff10").unwrap(), vec![0, 255, 16]);
+        assert_eq!(to_hex(&[0, 255, 16]), "00ff10");
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn btp_prepare_with_ilp_payment() {
+        let payment = IlpPayment {
+            amount: 100,
+            account: "example.bob".to_string(),
+            data: vec![1, 2],
+        };
+        let packet = BtpPacket {
+            packet_type: PacketType::Prepare,
+            request_id: 7,
+            data: PacketContents::Prepare(Prepare {
+                transfer_id: [1; 16],
+                amount: 10,
+                execution_condition: [2; 32],
+                expires_at: Utc.ymd(2017, 12, 23).and_hms(1, 2, 3),
+                protocol_data: vec![
+                    ProtocolData::ilp(payment.to_bytes().unwrap()),
+                    ProtocolData::text("memo", "hi"),
+                ],
+            }),
+        };
+        let json = decode(&to_hex(&packet.to_bytes().unwrap()), Encoding::Hex).unwrap();
+        assert_eq!(json["packet_type"], "Prepare");
+        assert_eq!(json["request_id"], 7);
+        assert_eq!(json["contents"]["amount"], 10);
+        assert_eq!(json["contents"]["expires_at"], "2017-12-23T01:02:03+00:00");
+        let ilp = &json["contents"]["protocol_data"][0]["data"];
+        assert_eq!(ilp["packet_type"], "IlpPayment");
+        assert_eq!(ilp["account"], "example.bob");
+        assert_eq!(ilp["data"], "0102");
+        assert_eq!(json["contents"]["protocol_data"][1]["data"], "hi");
+        assert!(format!("{}", packet).contains("\"account\": \"example.bob\""));
+    }
+
+    #[test]
+    fn ilqp_request() {
+        let request = IlqpBySourceRequest {
+            destination_account: "example.bob".to_string(),
+            source_amount: 9,
+            destination_hold_duration: 3000,
+        };
+        let input = base64::encode(&request.to_bytes().unwrap());
+        let json = decode(&input, Encoding::Base64).unwrap();
+        assert_eq!(json["packet_type"], "IlqpBySourceRequest");
+        assert_eq!(json["source_amount"], 9);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode("", Encoding::Hex).is_err());
+        assert!(decode("ff0102", Encoding::Hex).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        // Each of these made ilp_packet's decoders read past the end
+        for input in &["018501", "0103000000", "040402057f00", "060201ff", "08890100", "0d880000"] {
+            assert!(decode(input, Encoding::Hex).is_err(), "decoded {}", input);
+        }
+    }
+}
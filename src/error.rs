@@ -0,0 +1,318 @@
+// The crate-level error. Every module's error converts into it, keeping the codes and messages
+// peers send us, so callers can handle errors from any part of the stack in one place
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use ilp_packet::errors::ParseError;
+use btp_packet;
+use ilp_error;
+use ilp_error::IlpError;
+use psk;
+use psk2;
+use ilqp;
+use decode;
+#[cfg(feature = "plugin")] use plugin;
+#[cfg(feature = "plugin")] use chunked_payment;
+#[cfg(feature = "plugin")] use stream;
+#[cfg(feature = "spsp")] use spsp;
+#[cfg(feature = "spsp")] use spsp_server;
+#[cfg(feature = "config")] use config;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        // The peer answered our request with a BTP Error
+        Btp(code: String, name: String, data: String) {
+            description("peer responded with a BTP error")
+            display("BTP error {} {}: {}", code, name, data)
+        }
+        // A connector or the receiver rejected the ILP packet
+        Ilp(code: String, message: String, triggered_by: String) {
+            description("ILP packet was rejected")
+            display("ILP error {} ({}) triggered by {}: {}",
+                code, ilp_error::error_name(code).unwrap_or("Unknown Error"), triggered_by, message)
+        }
+        // The transfer was rejected without an ILP error saying why
+        Rejected(message: String) {
+            description(message)
+        }
+        // The peer couldn't be reached or the connection dropped
+        Network(message: String) {
+            description(message)
+        }
+        // Couldn't get the receiver's details from its SPSP endpoint
+        ReceiverLookup(message: String) {
+            description(message)
+        }
+        InvalidPacket(message: String) {
+            description(message)
+        }
+        // The other side broke the rules of PSK, SPSP or STREAM, or its data didn't decrypt
+        Protocol(message: String) {
+            description(message)
+        }
+        InvalidInput(message: String) {
+            description(message)
+        }
+        Other(message: String) {
+            description(message)
+        }
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> Option<&str> {
+        match *self {
+            Error::Btp(ref code, _, _) | Error::Ilp(ref code, _, _) => Some(code),
+            _ => None,
+        }
+    }
+
+    // Whether sending the same thing again could succeed. BTP error codes use the same
+    // F/T/R classes as ILP ones
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            Error::Btp(ref code, _, _) | Error::Ilp(ref code, _, _) => ilp_error::is_retryable_code(code),
+            Error::Network(_) | Error::ReceiverLookup(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        !self.is_retryable()
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Error::Btp(..) => "btp",
+            Error::Ilp(..) => "ilp",
+            Error::Rejected(_) => "rejected",
+            Error::Network(_) => "network",
+            Error::ReceiverLookup(_) => "receiver_lookup",
+            Error::InvalidPacket(_) => "invalid_packet",
+            Error::Protocol(_) => "protocol",
+            Error::InvalidInput(_) => "invalid_input",
+            Error::Other(_) => "other",
+        }
+    }
+}
+
+// For reporting errors to other programs
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Error", 4)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("retryable", &self.is_retryable())?;
+        state.end()
+    }
+}
+
+impl From<IlpError> for Error {
+    fn from(err: IlpError) -> Self {
+        // ILPv1 connectors put the details in the data. ILPv4 Rejects carry the message in the
+        // name instead (see IlpReject::to_ilp_error) and their data is often binary
+        let message = match String::from_utf8(err.data) {
+            Ok(ref data) if !data.is_empty() => data.to_string(),
+            _ => err.name,
+        };
+        Error::Ilp(err.code, message, err.triggered_by)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::InvalidPacket(err.to_string())
+    }
+}
+
+impl From<btp_packet::Error> for Error {
+    fn from(err: btp_packet::Error) -> Self {
+        Error::InvalidPacket(err.to_string())
+    }
+}
+
+impl From<psk::Error> for Error {
+    fn from(err: psk::Error) -> Self {
+        match err {
+            psk::Error::InvalidPacket(err) => Error::from(err),
+            psk::Error::InvalidEncoding(err) => Error::from(err),
+            err => Error::Protocol(err.to_string()),
+        }
+    }
+}
+
+impl From<psk2::Error> for Error {
+    fn from(err: psk2::Error) -> Self {
+        match err {
+            psk2::Error::Decryption => Error::Protocol(err.to_string()),
+            err => Error::InvalidPacket(err.to_string()),
+        }
+    }
+}
+
+impl From<ilqp::Error> for Error {
+    fn from(err: ilqp::Error) -> Self {
+        Error::InvalidPacket(err.to_string())
+    }
+}
+
+impl From<decode::Error> for Error {
+    fn from(err: decode::Error) -> Self {
+        Error::InvalidInput(err.to_string())
+    }
+}
+
+#[cfg(feature = "plugin")]
+impl From<plugin::Error> for Error {
+    fn from(err: plugin::Error) -> Self {
+        match err {
+            plugin::Error::ErrorResponse(code, name, data) => Error::Btp(code, name, data),
+            plugin::Error::Rejected(Some(ilp_error)) => Error::from(ilp_error),
+            plugin::Error::Rejected(None) => Error::Rejected(err.to_string()),
+            // The connection ended or failed before the peer answered
+            plugin::Error::Io(_) | plugin::Error::Ws(_) | plugin::Error::ConnectionClosed |
+            plugin::Error::NoFulfillment => Error::Network(err.to_string()),
+            plugin::Error::Serialization(err) => Error::from(err),
+            plugin::Error::InvalidPacket(err) => Error::from(err),
+            plugin::Error::DateTimeParse(_) | plugin::Error::InvalidParameter(_) => Error::InvalidInput(err.to_string()),
+            plugin::Error::WrongFulfillment | plugin::Error::UnexpectedResponse => Error::Protocol(err.to_string()),
+            plugin::Error::NotConnected(_) | plugin::Error::PeerHandlerPanicked => Error::Other(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "plugin")]
+impl From<chunked_payment::Error> for Error {
+    fn from(err: chunked_payment::Error) -> Self {
+        match err {
+            chunked_payment::Error::Plugin(err) => Error::from(err),
+            chunked_payment::Error::Psk2(err) => Error::from(err),
+            chunked_payment::Error::Rejected(ilp_error, _) => Error::from(ilp_error),
+            err => Error::Other(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "plugin")]
+impl From<stream::Error> for Error {
+    fn from(err: stream::Error) -> Self {
+        match err {
+            stream::Error::Plugin(err) => Error::from(err),
+            stream::Error::Encryption(err) => Error::from(err),
+            stream::Error::InvalidPacket(err) => Error::from(err),
+            stream::Error::InvalidEncoding(err) => Error::from(err),
+            stream::Error::NoExchangeRate => Error::InvalidInput(err.to_string()),
+            stream::Error::Io(_) | stream::Error::Invalid(_) => Error::InvalidPacket(err.to_string()),
+            err => Error::Protocol(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "spsp")]
+impl From<spsp::Error> for Error {
+    fn from(err: spsp::Error) -> Self {
+        match err {
+            // The only HTTP request is to the receiver's SPSP endpoint
+            spsp::Error::Reqwest(_) => Error::ReceiverLookup(err.to_string()),
+            spsp::Error::Ilqp(err) => Error::from(err),
+            spsp::Error::Plugin(err) => Error::from(err),
+            spsp::Error::Psk(err) => Error::from(err),
+            spsp::Error::InvalidSharedSecret(_) => Error::Protocol(err.to_string()),
+            spsp::Error::InvalidHoldDuration(_) => Error::InvalidInput(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "spsp")]
+impl From<spsp_server::Error> for Error {
+    fn from(err: spsp_server::Error) -> Self {
+        match err {
+            spsp_server::Error::Plugin(err) => Error::from(err),
+            err => Error::Network(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "config")]
+impl From<config::Error> for Error {
+    fn from(err: config::Error) -> Self {
+        Error::InvalidInput(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod classification {
+    use super::*;
+    use chrono::Utc;
+    use serde_json;
+
+    #[test]
+    fn ilp_errors() {
+        let error = Error::from(IlpError {
+            code: "T04".to_string(),
+            name: "Insufficient Liquidity".to_string(),
+            triggered_by: "example.connie".to_string(),
+            forwarded_by: Vec::new(),
+            triggered_at: Utc::now(),
+            data: b"connector is out of money".to_vec(),
+        });
+        assert_eq!(error.code(), Some("T04"));
+        assert!(error.is_retryable());
+        assert_eq!(error.to_string(),
+            "ILP error T04 (Insufficient Liquidity) triggered by example.connie: connector is out of money");
+
+        let reject = IlpError {
+            code: "F99".to_string(),
+            name: "no such account".to_string(),
+            triggered_by: "example.bob".to_string(),
+            forwarded_by: Vec::new(),
+            triggered_at: Utc::now(),
+            data: vec![0xff, 0x00],
+        };
+        assert_eq!(Error::from(reject).to_string(),
+            "ILP error F99 (Application Error) triggered by example.bob: no such account");
+    }
+
+    #[test]
+    fn btp_errors() {
+        let error = Error::Btp("F00".to_string(), "NotAcceptedError".to_string(), "bad request".to_string());
+        assert!(error.is_final());
+        assert_eq!(error.to_string(), "BTP error F00 NotAcceptedError: bad request");
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["kind"], "btp");
+        assert_eq!(json["code"], "F00");
+        assert_eq!(json["retryable"], false);
+    }
+
+    #[test]
+    fn module_errors() {
+        assert!(Error::from(psk2::Error::Decryption).is_final());
+        assert!(Error::from(decode::Error::UnknownPacket).is_final());
+        match Error::from(btp_packet::Error::TrailingBytes(3)) {
+            Error::InvalidPacket(message) => assert!(message.contains("offset 3")),
+            other => panic!("expected an invalid packet error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "plugin")]
+    #[test]
+    fn plugin_errors() {
+        let error = Error::from(plugin::Error::ErrorResponse("T00".to_string(), "UnreachableError".to_string(), String::new()));
+        assert_eq!(error.code(), Some("T00"));
+        assert!(error.is_retryable());
+
+        let error = Error::from(plugin::Error::Rejected(None));
+        assert_eq!(error.kind(), "rejected");
+        assert!(error.is_final());
+        assert_eq!(Error::from(plugin::Error::NoFulfillment).kind(), "network");
+        assert_eq!(Error::from(plugin::Error::UnexpectedResponse).kind(), "protocol");
+    }
+
+    #[cfg(feature = "spsp")]
+    #[test]
+    fn spsp_errors() {
+        let err = ::base64::decode_config("not base64!", ::base64::URL_SAFE_NO_PAD).unwrap_err();
+        assert_eq!(Error::from(spsp::Error::InvalidSharedSecret(err)).kind(), "protocol");
+    }
+}
@@ -0,0 +1,169 @@
+use std::io::{Cursor, Read, Write};
+use std::str;
+use ilp_packet::oer::WriteOerExt;
+use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use chrono::{DateTime, Utc};
+use btp_packet::{GeneralizedTime, Error, read_var_uint, write_var_uint, read_var_octet_slice};
+
+const ILP_ERROR_TYPE: u8 = 8;
+
+// F errors are final, T errors are temporary and R errors are relative to the payment's
+// amounts or timeouts, so the payment can be retried after adjusting them
+const ERROR_NAMES: &[(&str, &str)] = &[
+    ("F00", "Bad Request"),
+    ("F01", "Invalid Packet"),
+    ("F02", "Unreachable"),
+    ("F03", "Invalid Amount"),
+    ("F04", "Insufficient Destination Amount"),
+    ("F05", "Wrong Condition"),
+    ("F06", "Unexpected Payment"),
+    ("F07", "Cannot Receive"),
+    ("F08", "Amount Too Large"),
+    ("F99", "Application Error"),
+    ("T00", "Internal Error"),
+    ("T01", "Peer Unreachable"),
+    ("T02", "Peer Busy"),
+    ("T03", "Connector Busy"),
+    ("T04", "Insufficient Liquidity"),
+    ("T05", "Rate Limited"),
+    ("T99", "Application Error"),
+    ("R00", "Transfer Timed Out"),
+    ("R01", "Insufficient Source Amount"),
+    ("R02", "Insufficient Timeout"),
+    ("R99", "Application Error"),
+];
+
+pub fn error_name(code: &str) -> Option<&'static str> {
+    ERROR_NAMES.iter()
+        .find(|&&(known_code, _)| known_code == code)
+        .map(|&(_, name)| name)
+}
+
+// Whether the same payment could succeed if it is sent again, possibly with different amounts
+pub fn is_retryable_code(code: &str) -> bool {
+    code.starts_with('T') || code.starts_with('R')
+}
+
+// ILP error packet, carried in the "ilp" protocol data of rejected transfers
+#[derive(Debug, PartialEq, Clone)]
+pub struct IlpError {
+    pub code: String, // 3 ASCII characters
+    pub name: String,
+    pub triggered_by: String,
+    pub forwarded_by: Vec<String>,
+    pub triggered_at: DateTime<Utc>,
+    pub data: Vec<u8>,
+}
+
+impl IlpError {
+    pub fn from_bytes(bytes: &[u8]) -> Result<IlpError, Error> {
+        let mut envelope = Cursor::new(bytes);
+        if envelope.read_u8()? != ILP_ERROR_TYPE {
+            return Err(Error::Invalid("packet is not an ILP error"));
+        }
+        let contents = read_var_octet_slice(&mut envelope)?;
+
+        // Peers send these, so every length is checked against the bytes that are left
+        let mut reader = Cursor::new(contents);
+        let mut code = [0u8; 3];
+        reader.read_exact(&mut code)?;
+        let code = String::from_utf8(code.to_vec())?;
+        let name = str::from_utf8(read_var_octet_slice(&mut reader)?)?.to_string();
+        let triggered_by = str::from_utf8(read_var_octet_slice(&mut reader)?)?.to_string();
+        let forwarded_by_length = read_var_uint(&mut reader)?;
+        let mut forwarded_by = Vec::new();
+        for _i in 0..forwarded_by_length {
+            forwarded_by.push(str::from_utf8(read_var_octet_slice(&mut reader)?)?.to_string());
+        }
+        let triggered_at = GeneralizedTime::from_bytes(read_var_octet_slice(&mut reader)?)?.0;
+        let data = read_var_octet_slice(&mut reader)?.to_vec();
+        Ok(IlpError {
+            code,
+            name,
+            triggered_by,
+            forwarded_by,
+            triggered_at,
+            data,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        if self.code.len() != 3 || !self.code.is_ascii() {
+            return Err(Error::Invalid("code must be 3 ASCII characters"));
+        }
+        let mut contents: Vec<u8> = Vec::new();
+        contents.write_all(self.code.as_bytes())?;
+        contents.write_var_octet_string(self.name.as_bytes())?;
+        contents.write_var_octet_string(self.triggered_by.as_bytes())?;
+        write_var_uint(&mut contents, self.forwarded_by.len() as u64)?;
+        for address in &self.forwarded_by {
+            contents.write_var_octet_string(address.as_bytes())?;
+        }
+        contents.write_var_octet_string(&GeneralizedTime(self.triggered_at).to_bytes())?;
+        contents.write_var_octet_string(&self.data)?;
+        contents.write_u8(0)?; // extensibility
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_u8(ILP_ERROR_TYPE)?;
+        bytes.write_var_octet_string(&contents)?;
+        Ok(bytes)
+    }
+
+    // Errors starting with T are temporary and the payment can be retried
+    pub fn is_temporary(&self) -> bool {
+        self.code.starts_with('T')
+    }
+
+    // F08 errors carry the amount the connector received and the maximum it would forward
+    pub fn amount_too_large(&self) -> Option<(u64, u64)> {
+        if self.code != "F08" {
+            return None;
+        }
+        let mut reader = Cursor::new(&self.data[..]);
+        match (reader.read_u64::<BigEndian>(), reader.read_u64::<BigEndian>()) {
+            (Ok(received_amount), Ok(maximum_amount)) => Some((received_amount, maximum_amount)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod errors {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn round_trips() {
+        let mut data = Vec::new();
+        data.write_u64::<BigEndian>(1000).unwrap();
+        data.write_u64::<BigEndian>(100).unwrap();
+        let error = IlpError {
+            code: "F08".to_string(),
+            name: "Amount Too Large".to_string(),
+            triggered_by: "example.connie".to_string(),
+            forwarded_by: vec!["example.connie".to_string(), "example.mark".to_string()],
+            triggered_at: Utc.timestamp(1_505_444_840, 870_000_000),
+            data,
+        };
+        let parsed = IlpError::from_bytes(&error.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed, error);
+        assert_eq!(parsed.amount_too_large(), Some((1000, 100)));
+        assert!(!parsed.is_temporary());
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        assert!(IlpError::from_bytes(&[8, 0x89, 1, 2]).is_err());
+        assert!(IlpError::from_bytes(&[8, 0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(IlpError::from_bytes(&[8, 5, b'F', b'0', b'0', 0x7f, 0]).is_err());
+    }
+
+    #[test]
+    fn classifies_codes() {
+        assert_eq!(error_name("T04"), Some("Insufficient Liquidity"));
+        assert_eq!(error_name("X00"), None);
+        assert!(is_retryable_code("T04"));
+        assert!(is_retryable_code("R00"));
+        assert!(!is_retryable_code("F02"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::io::{Cursor, Read, Write};
+use std::str;
+use ilp_packet::oer::WriteOerExt;
+use ilp_packet::packet::IlpPayment;
+use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use chrono::{DateTime, Utc, NaiveDateTime};
+use btp_packet::{Error, read_var_octet_slice};
+use ilp_error::IlpError;
+
+// The ILP packets BTP 2.0 peers exchange instead of BTP 1.0 Prepare/Fulfill/Reject
+const ILP_PAYMENT_TYPE: u8 = 1;
+const PREPARE_TYPE: u8 = 12;
+const FULFILL_TYPE: u8 = 13;
+const REJECT_TYPE: u8 = 14;
+// Unlike in BTP, timestamps are a fixed 17 digits with no separators
+const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S%3f";
+const TIMESTAMP_LENGTH: usize = 17;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IlpPrepare {
+    pub amount: u64,
+    pub expires_at: DateTime<Utc>,
+    pub execution_condition: [u8; 32],
+    pub destination: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IlpFulfill {
+    pub fulfillment: [u8; 32],
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IlpReject {
+    pub code: String, // 3 ASCII characters
+    pub triggered_by: String,
+    pub message: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum IlpPacket {
+    Prepare(IlpPrepare),
+    Fulfill(IlpFulfill),
+    Reject(IlpReject),
+}
+
+fn write_envelope(packet_type: u8, contents: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.write_u8(packet_type)?;
+    bytes.write_var_octet_string(contents)?;
+    Ok(bytes)
+}
+
+impl IlpPacket {
+    pub fn from_bytes(bytes: &[u8]) -> Result<IlpPacket, Error> {
+        let mut envelope = Cursor::new(bytes);
+        let packet_type = envelope.read_u8()?;
+        let contents = read_var_octet_slice(&mut envelope)?;
+        let mut reader = Cursor::new(contents);
+        match packet_type {
+            PREPARE_TYPE => {
+                let amount = reader.read_u64::<BigEndian>()?;
+                let mut expires_at = [0u8; TIMESTAMP_LENGTH];
+                reader.read_exact(&mut expires_at)?;
+                let expires_at = NaiveDateTime::parse_from_str(&String::from_utf8(expires_at.to_vec())?, TIMESTAMP_FORMAT)?;
+                let mut execution_condition = [0u8; 32];
+                reader.read_exact(&mut execution_condition)?;
+                let destination = str::from_utf8(read_var_octet_slice(&mut reader)?)?.to_string();
+                let data = read_var_octet_slice(&mut reader)?.to_vec();
+                Ok(IlpPacket::Prepare(IlpPrepare {
+                    amount,
+                    expires_at: DateTime::<Utc>::from_utc(expires_at, Utc),
+                    execution_condition,
+                    destination,
+                    data,
+                }))
+            },
+            FULFILL_TYPE => {
+                let mut fulfillment = [0u8; 32];
+                reader.read_exact(&mut fulfillment)?;
+                let data = read_var_octet_slice(&mut reader)?.to_vec();
+                Ok(IlpPacket::Fulfill(IlpFulfill {
+                    fulfillment,
+                    data,
+                }))
+            },
+            REJECT_TYPE => {
+                let mut code = [0u8; 3];
+                reader.read_exact(&mut code)?;
+                let code = String::from_utf8(code.to_vec())?;
+                let triggered_by = str::from_utf8(read_var_octet_slice(&mut reader)?)?.to_string();
+                let message = str::from_utf8(read_var_octet_slice(&mut reader)?)?.to_string();
+                let data = read_var_octet_slice(&mut reader)?.to_vec();
+                Ok(IlpPacket::Reject(IlpReject {
+                    code,
+                    triggered_by,
+                    message,
+                    data,
+                }))
+            },
+            _ => Err(Error::Invalid("unknown ILP packet type")),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut contents: Vec<u8> = Vec::new();
+        match *self {
+            IlpPacket::Prepare(ref prepare) => {
+                contents.write_u64::<BigEndian>(prepare.amount)?;
+                contents.write_all(prepare.expires_at.naive_utc().format(TIMESTAMP_FORMAT).to_string().as_bytes())?;
+                contents.write_all(&prepare.execution_condition)?;
+                contents.write_var_octet_string(prepare.destination.as_bytes())?;
+                contents.write_var_octet_string(&prepare.data)?;
+                write_envelope(PREPARE_TYPE, &contents)
+            },
+            IlpPacket::Fulfill(ref fulfill) => {
+                contents.write_all(&fulfill.fulfillment)?;
+                contents.write_var_octet_string(&fulfill.data)?;
+                write_envelope(FULFILL_TYPE, &contents)
+            },
+            IlpPacket::Reject(ref reject) => {
+                if reject.code.len() != 3 || !reject.code.is_ascii() {
+                    return Err(Error::Invalid("code must be 3 ASCII characters"));
+                }
+                contents.write_all(reject.code.as_bytes())?;
+                contents.write_var_octet_string(reject.triggered_by.as_bytes())?;
+                contents.write_var_octet_string(reject.message.as_bytes())?;
+                contents.write_var_octet_string(&reject.data)?;
+                write_envelope(REJECT_TYPE, &contents)
+            },
+        }
+    }
+}
+
+impl IlpReject {
+    // So code that handles ILPv1 errors, like the chunked sender, works with either version
+    pub fn to_ilp_error(&self) -> IlpError {
+        IlpError {
+            code: self.code.to_string(),
+            name: self.message.to_string(),
+            triggered_by: self.triggered_by.to_string(),
+            forwarded_by: Vec::new(),
+            triggered_at: Utc::now(),
+            data: self.data.clone(),
+        }
+    }
+}
+
+// Decodes an ILPv1 payment, checking every length against the bytes that are left. Use this
+// instead of IlpPayment::from_bytes on packets from peers, since that panics on bad lengths
+pub fn read_ilp_payment(bytes: &[u8]) -> Result<IlpPayment, Error> {
+    let mut envelope = Cursor::new(bytes);
+    if envelope.read_u8()? != ILP_PAYMENT_TYPE {
+        return Err(Error::Invalid("packet is not an ILP payment"));
+    }
+    let mut reader = Cursor::new(read_var_octet_slice(&mut envelope)?);
+    let amount = reader.read_u64::<BigEndian>()?;
+    let account = str::from_utf8(read_var_octet_slice(&mut reader)?)?.to_string();
+    let data = read_var_octet_slice(&mut reader)?.to_vec();
+    Ok(IlpPayment {
+        amount,
+        account,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod packets {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn round_trips() {
+        let packets = vec![
+            IlpPacket::Prepare(IlpPrepare {
+                amount: 107,
+                expires_at: Utc.timestamp(1_505_444_840, 870_000_000),
+                execution_condition: [9; 32],
+                destination: "example.alice".to_string(),
+                data: b"hello".to_vec(),
+            }),
+            IlpPacket::Fulfill(IlpFulfill {
+                fulfillment: [7; 32],
+                data: Vec::new(),
+            }),
+            IlpPacket::Reject(IlpReject {
+                code: "F99".to_string(),
+                triggered_by: "example.connie".to_string(),
+                message: "oops".to_string(),
+                data: vec![1, 2],
+            }),
+        ];
+        for packet in packets {
+            assert_eq!(IlpPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        assert!(IlpPacket::from_bytes(&[13, 0x89, 1]).is_err());
+        assert!(IlpPacket::from_bytes(&[13, 0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        let mut fulfill = IlpPacket::Fulfill(IlpFulfill {
+            fulfillment: [7; 32],
+            data: b"hi".to_vec(),
+        }).to_bytes().unwrap();
+        fulfill[34] = 0x7f;
+        assert!(IlpPacket::from_bytes(&fulfill).is_err());
+    }
+
+    #[test]
+    fn reads_ilp_payments() {
+        let payment = IlpPayment {
+            amount: 100,
+            account: "example.bob".to_string(),
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(read_ilp_payment(&payment.to_bytes().unwrap()).unwrap(), payment);
+        assert!(read_ilp_payment(&[1, 3, 0, 0, 0]).is_err());
+        assert!(read_ilp_payment(&[1, 0x85, 1]).is_err());
+    }
+
+    #[test]
+    fn writes_fixed_length_timestamps() {
+        let prepare = IlpPacket::Prepare(IlpPrepare {
+            amount: 0,
+            expires_at: Utc.timestamp(1_505_444_840, 870_000_000),
+            execution_condition: [0; 32],
+            destination: String::new(),
+            data: Vec::new(),
+        });
+        let bytes = prepare.to_bytes().unwrap();
+        assert_eq!(&bytes[10..27], b"20170915030720870");
+    }
+}
@@ -18,7 +18,7 @@ pub fn quote_source(destination_account: &str, source_amount: u64, destination_h
         destination_hold_duration,
     };
     let ilqp_packet = request.to_bytes()?;
-    println!("{:?}", ilqp_packet);
+    trace!("ILQP request: {:?}", ilqp_packet);
     Ok(0)
 }
 
@@ -29,6 +29,6 @@ pub fn quote_destination(destination_account: &str, destination_amount: u64, des
         destination_hold_duration,
     };
     let ilqp_packet = request.to_bytes()?;
-    println!("{:?}", ilqp_packet);
+    trace!("ILQP request: {:?}", ilqp_packet);
     Ok(0)
 }
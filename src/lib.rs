@@ -0,0 +1,45 @@
+extern crate serde;
+#[macro_use] extern crate serde_json;
+#[cfg_attr(any(test, feature = "plugin", feature = "config"), macro_use)] extern crate serde_derive;
+#[macro_use] extern crate quick_error;
+#[macro_use] extern crate log;
+extern crate ilp_packet;
+extern crate rand;
+extern crate base64;
+extern crate ring;
+extern crate aes;
+extern crate ghash;
+extern crate chrono;
+extern crate byteorder;
+#[cfg(test)] #[macro_use] extern crate proptest;
+#[cfg(feature = "tokio")] extern crate tokio_io;
+#[cfg(feature = "tokio")] extern crate bytes;
+#[cfg(feature = "plugin")] extern crate uuid;
+#[cfg(feature = "plugin")] extern crate futures;
+#[cfg(feature = "plugin")] extern crate websocket;
+#[cfg(feature = "plugin")] extern crate tokio_core;
+#[cfg(feature = "plugin")] extern crate regex;
+#[cfg(feature = "plugin")] #[macro_use] extern crate lazy_static;
+#[cfg(feature = "spsp")] extern crate reqwest;
+#[cfg(feature = "spsp")] extern crate hyper;
+#[cfg(feature = "config")] extern crate toml;
+#[cfg(any(feature = "plugin", feature = "config"))] extern crate percent_encoding;
+
+// Only the plugin and SPSP code logs secrets
+#[cfg(feature = "plugin")] #[macro_use] mod redact;
+mod error;
+pub use error::Error;
+
+pub mod btp_packet;
+pub mod psk;
+pub mod psk2;
+pub mod ilqp;
+pub mod ilp_error;
+pub mod ilpv4;
+pub mod decode;
+#[cfg(feature = "plugin")] pub mod plugin;
+#[cfg(feature = "plugin")] pub mod chunked_payment;
+#[cfg(feature = "plugin")] pub mod stream;
+#[cfg(feature = "spsp")] pub mod spsp;
+#[cfg(feature = "spsp")] pub mod spsp_server;
+#[cfg(feature = "config")] pub mod config;
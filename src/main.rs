@@ -1,42 +1,72 @@
 extern crate clap;
-extern crate reqwest;
-extern crate serde;
+#[macro_use]
 extern crate serde_json;
-#[macro_use] extern crate serde_derive;
-#[macro_use] extern crate quick_error;
-extern crate ilp_packet;
-extern crate rand;
-extern crate base64;
-extern crate ring;
-extern crate uuid;
-extern crate chrono;
-extern crate byteorder;
-extern crate futures;
-extern crate websocket;
-extern crate tokio_core;
-extern crate regex;
-#[macro_use] extern crate lazy_static;
+extern crate log;
+extern crate env_logger;
+extern crate ilp;
 
-use clap::{App, SubCommand, Arg};
+use std::env;
+use std::process;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use clap::{App, SubCommand, Arg, ArgMatches};
+use log::LogLevelFilter;
+use env_logger::LogBuilder;
+use ilp::{spsp, psk, decode, Error};
+use ilp::plugin::Plugin;
+use ilp::spsp::{LedgerInfo, ReceiverInfo};
+use ilp::spsp_server::SpspServer;
+use ilp::config::{Config, Profile};
 
-// TODO move all of these to lib.rs or separate crates
-mod spsp;
-mod ilqp;
-mod psk;
-mod plugin;
-mod btp_packet;
+// Exit codes, so scripts can tell what kind of failure happened without parsing the output
+const EXIT_FAILURE: i32 = 1;
+const EXIT_INVALID_INPUT: i32 = 2;
+const EXIT_RECEIVER_LOOKUP: i32 = 3;
+const EXIT_QUOTE: i32 = 4;
+const EXIT_REJECTED: i32 = 5;
+const EXIT_EXPIRED: i32 = 6;
+const EXIT_CONNECTION: i32 = 7;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Text,
+    Json,
+}
 
 fn main() {
     let matches = App::new("spsp")
         .version("0.1.0")
         .author("Evan Schwartz <evan@ripple.com>")
         .about("Command line sending client for ILP/SPSP")
+        .arg(Arg::with_name("verbose")
+             .short("v")
+             .multiple(true)
+             .global(true)
+             .help("Log more to stderr (-vvv logs everything, including secrets)"))
+        .arg(Arg::with_name("profile")
+             .takes_value(true)
+             .long("profile")
+             .global(true)
+             .help("Profile from the config file to use"))
+        .arg(Arg::with_name("config")
+             .takes_value(true)
+             .long("config")
+             .global(true)
+             .help("Config file to use instead of ~/.config/ilp/config.toml"))
+        .arg(Arg::with_name("output")
+             .takes_value(true)
+             .long("output")
+             .global(true)
+             .possible_values(&["text", "json"])
+             .default_value("text")
+             .help("Print results and errors as text or JSON"))
         .subcommand(SubCommand::with_name("quote")
                     .about("Get a quote")
                     .arg(Arg::with_name("btp_server")
                          .takes_value(true)
                          .long("btp_server")
-                         .required(true))
+                         .help("Overrides the profile's btp_server"))
                     .arg(Arg::with_name("source_amount")
                          .takes_value(true)
                          .long("source_amount")
@@ -55,7 +85,7 @@ fn main() {
                     .arg(Arg::with_name("btp_server")
                          .takes_value(true)
                          .long("btp_server")
-                         .required(true))
+                         .help("Overrides the profile's btp_server"))
                     .arg(Arg::with_name("source_amount")
                          .takes_value(true)
                          .long("source_amount")
@@ -64,37 +94,257 @@ fn main() {
                          .takes_value(true)
                          .long("destination_amount")
                          .required(true))
+                    .arg(Arg::with_name("memo")
+                         .takes_value(true)
+                         .long("memo")
+                         .help("Data to send to the receiver along with the payment"))
+                    .arg(Arg::with_name("scale")
+                         .takes_value(true)
+                         .long("scale")
+                         .help("Decimal places of the source account's currency"))
+                    .arg(Arg::with_name("hold_duration")
+                         .takes_value(true)
+                         .long("hold_duration")
+                         .help("Seconds the transfer is held for"))
                     .arg(Arg::with_name("receiver")
                         .index(1)
                         .required(true)))
+        .subcommand(SubCommand::with_name("receive")
+                    .about("Run an SPSP receiver and print the payments it gets")
+                    .arg(Arg::with_name("btp_server")
+                         .takes_value(true)
+                         .long("btp_server")
+                         .help("Overrides the profile's btp_server"))
+                    .arg(Arg::with_name("listen")
+                         .takes_value(true)
+                         .long("listen")
+                         .default_value("127.0.0.1:3000")
+                         .help("Address to serve the SPSP endpoint on"))
+                    .arg(Arg::with_name("ilp_address")
+                         .takes_value(true)
+                         .long("ilp_address")
+                         .default_value("test.receiver")
+                         .help("ILP address of the account the BTP server gave us"))
+                    .arg(Arg::with_name("currency_code")
+                         .takes_value(true)
+                         .long("currency_code")
+                         .default_value("XRP"))
+                    .arg(Arg::with_name("currency_scale")
+                         .takes_value(true)
+                         .long("currency_scale")
+                         .help("Defaults to the profile's scale, or 9")))
+        .subcommand(SubCommand::with_name("decode")
+                    .about("Print a BTP packet, ILP payment or ILQP packet as JSON")
+                    .arg(Arg::with_name("hex")
+                         .long("hex")
+                         .conflicts_with("base64")
+                         .help("Input is hex (the default)"))
+                    .arg(Arg::with_name("base64")
+                         .long("base64")
+                         .help("Input is base64"))
+                    .arg(Arg::with_name("input")
+                        .index(1)
+                        .required(true)))
         .get_matches();
+    init_logger(matches.occurrences_of("verbose"));
     match matches.subcommand_name() {
         Some("quote") => {
             let matches = matches.subcommand_matches("quote").unwrap();
             let receiver = matches.value_of("receiver").unwrap();
-            let btp_server = matches.value_of("btp_server").unwrap();
-            if matches.is_present("source_amount") {
-                let source_amount: f64 = matches.value_of("source_amount").unwrap().parse().unwrap();
-                let destination_amount = spsp::quote_source(btp_server, receiver, source_amount);
-                println!("{}", destination_amount.unwrap())
+            let output = output_format(matches);
+            let (btp_server, profile) = match load_profile(matches) {
+                Ok(result) => result,
+                Err(err) => fail("quote", output, "Error loading config", err),
+            };
+            let source_scale = profile.scale.unwrap_or(spsp::PayOptions::default().source_scale);
+            let by_source = matches.is_present("source_amount");
+            let result = if by_source {
+                let source_amount: f64 = parse_arg("quote", output, matches, "source_amount").unwrap();
+                spsp::quote_by_source(&btp_server, receiver, source_amount, source_scale)
             } else {
-                let destination_amount: f64 = matches.value_of("destination_amount").unwrap().parse().unwrap();
-                let source_amount = spsp::quote_destination(btp_server, receiver, destination_amount);
-                println!("{}", source_amount.unwrap())
+                let destination_amount: f64 = parse_arg("quote", output, matches, "destination_amount").unwrap();
+                spsp::quote_by_destination(&btp_server, receiver, destination_amount, source_scale)
+            };
+            match result {
+                Ok(ref quote) if output == Output::Json => println!("{}", serde_json::to_string_pretty(quote).unwrap()),
+                Ok(ref quote) if by_source => println!("{}", quote.destination_amount_decimal()),
+                Ok(quote) => println!("{}", quote.source_amount_decimal()),
+                Err(err) => fail("quote", output, "Error getting quote", Error::from(err)),
             }
         },
         Some("pay") => {
             let matches = matches.subcommand_matches("pay").unwrap();
             let receiver = matches.value_of("receiver").unwrap();
-            let btp_server = matches.value_of("btp_server").unwrap();
-            let source_amount: f64 = matches.value_of("source_amount").unwrap().parse().unwrap();
-            let destination_amount: f64 = matches.value_of("destination_amount").unwrap().parse().unwrap();
-            match spsp::pay(btp_server, receiver, source_amount, destination_amount) {
-                Ok(_result) => println!("Sent payment"),
-                Err(err) => println!("Error sending payment: {:?}", err),
+            let output = output_format(matches);
+            let (btp_server, profile) = match load_profile(matches) {
+                Ok(result) => result,
+                Err(err) => fail("pay", output, "Error loading config", err),
+            };
+            let source_amount: f64 = parse_arg("pay", output, matches, "source_amount").unwrap();
+            let destination_amount: f64 = parse_arg("pay", output, matches, "destination_amount").unwrap();
+            let defaults = spsp::PayOptions::default();
+            let options = spsp::PayOptions {
+                memo: psk::PskMemo {
+                    data: matches.value_of("memo").unwrap_or("").as_bytes().to_vec(),
+                    ..psk::PskMemo::default()
+                },
+                source_scale: parse_arg("pay", output, matches, "scale")
+                    .or(profile.scale)
+                    .unwrap_or(defaults.source_scale),
+                hold_duration: parse_arg("pay", output, matches, "hold_duration")
+                    .or(profile.hold_duration)
+                    .unwrap_or(defaults.hold_duration),
+            };
+            match spsp::pay_with_options(&btp_server, receiver, source_amount, destination_amount, &options) {
+                Ok(ref receipt) if output == Output::Json => println!("{}", serde_json::to_string_pretty(receipt).unwrap()),
+                Ok(receipt) => println!("Sent {} to {} in transfer {}",
+                    receipt.source_amount, receipt.destination_account, receipt.transfer_id),
+                Err(err) => fail("pay", output, "Error sending payment", Error::from(err)),
+            }
+        },
+        Some("receive") => {
+            let matches = matches.subcommand_matches("receive").unwrap();
+            let output = output_format(matches);
+            let (btp_server, profile) = match load_profile(matches) {
+                Ok(result) => result,
+                Err(err) => fail("receive", output, "Error loading config", err),
+            };
+            let listen: SocketAddr = match matches.value_of("listen").unwrap().parse() {
+                Ok(listen) => listen,
+                Err(_) => fail("receive", output, "Invalid --listen",
+                    Error::InvalidInput("--listen must be an address like 127.0.0.1:3000".to_string())),
+            };
+            let plugin = match Plugin::new(&btp_server) {
+                Ok(plugin) => plugin,
+                Err(err) => fail("receive", output, "Error connecting to BTP server", Error::from(err)),
+            };
+            let mut server = SpspServer::new(matches.value_of("ilp_address").unwrap(), LedgerInfo {
+                currency_code: matches.value_of("currency_code").unwrap().to_string(),
+                currency_scale: parse_arg("receive", output, matches, "currency_scale")
+                    .or(profile.scale)
+                    .unwrap_or(9),
+            });
+            // Served at the root so the endpoint is just the listen address
+            server.add_receiver(ReceiverInfo {
+                name: String::new(),
+                image_url: String::new(),
+                identifier: format!("http://{}", listen),
+            });
+            // In JSON mode stdout only has one line per payment
+            match output {
+                Output::Json => eprintln!("Receiving payments at http://{}", listen),
+                Output::Text => println!("Receiving payments at http://{}", listen),
+            }
+            let result = server.run_with_handler(&listen, plugin, move |payment| {
+                // BTP 2.0 transfers have no id to show
+                let transfer_id = payment.transfer_id.map(|id| decode::to_hex(&id));
+                let memo = String::from_utf8_lossy(&payment.memo.data);
+                match output {
+                    Output::Json => println!("{}", json!({
+                        "amount": payment.amount,
+                        "transfer_id": transfer_id,
+                        "destination_account": payment.destination_account,
+                        "memo": memo,
+                    })),
+                    Output::Text => println!("Received {}{} with memo {:?}",
+                        payment.amount,
+                        transfer_id.map(|id| format!(" in transfer {}", id)).unwrap_or_default(),
+                        memo),
+                }
+            });
+            if let Err(err) = result {
+                fail("receive", output, "Error receiving payments", Error::from(err));
             }
         },
-        Some(command) => println!("unknown command: {}", command),
-        None => println!("command is required")
+        Some("decode") => {
+            let matches = matches.subcommand_matches("decode").unwrap();
+            let input = matches.value_of("input").unwrap();
+            let encoding = if matches.is_present("base64") {
+                decode::Encoding::Base64
+            } else {
+                decode::Encoding::Hex
+            };
+            match decode::decode(input, encoding) {
+                Ok(json) => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
+                Err(err) => fail("decode", output_format(matches), "Error decoding packet", Error::from(err)),
+            }
+        },
+        Some(command) => fail(command, output_format(&matches), "Invalid arguments",
+            Error::InvalidInput(format!("unknown command: {}", command))),
+        None => fail("", output_format(&matches), "Invalid arguments",
+            Error::InvalidInput("command is required".to_string())),
+    }
+}
+
+fn output_format(matches: &ArgMatches) -> Output {
+    match matches.value_of("output") {
+        Some("json") => Output::Json,
+        _ => Output::Text,
+    }
+}
+
+// Prints the error, to stdout as {"error": ...} in JSON mode so it can be parsed like a
+// result, then exits with the code for its class
+fn fail(command: &str, output: Output, context: &str, err: Error) -> ! {
+    match output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&json!({ "error": err })).unwrap()),
+        Output::Text => eprintln!("{}: {}", context, err),
+    }
+    process::exit(exit_code(command, &err))
+}
+
+fn exit_code(command: &str, err: &Error) -> i32 {
+    match *err {
+        Error::InvalidInput(_) => EXIT_INVALID_INPUT,
+        Error::ReceiverLookup(_) => EXIT_RECEIVER_LOOKUP,
+        Error::Network(_) => EXIT_CONNECTION,
+        _ if command == "quote" => EXIT_QUOTE,
+        // Every R code means the transfer expired on the way
+        Error::Ilp(ref code, _, _) | Error::Btp(ref code, _, _) if code.starts_with('R') => EXIT_EXPIRED,
+        Error::Ilp(..) | Error::Btp(..) | Error::Rejected(_) => EXIT_REJECTED,
+        _ => EXIT_FAILURE,
+    }
+}
+
+// The flag's value if it was given. Exits with EXIT_INVALID_INPUT if it isn't a valid number
+fn parse_arg<T: FromStr>(command: &str, output: Output, matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|value| match value.parse() {
+        Ok(parsed) => parsed,
+        Err(_) => fail(command, output, "Invalid arguments",
+            Error::InvalidInput(format!("--{} must be a number, not {:?}", name, value))),
+    })
+}
+
+// Returns the BTP server and the rest of the profile, with the command line flags taking
+// precedence over the config file
+fn load_profile(matches: &ArgMatches) -> Result<(String, Profile), Error> {
+    let config = match matches.value_of("config") {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::load_default()?,
+    };
+    let profile = config.profile(matches.value_of("profile"))?;
+    let btp_server = match matches.value_of("btp_server") {
+        Some(btp_server) => btp_server.to_string(),
+        None => profile.btp_server_with_credentials()?
+            .ok_or_else(|| Error::InvalidInput("--btp_server is required if the profile doesn't set one".to_string()))?,
+    };
+    Ok((btp_server, profile))
+}
+
+// Logs go to stderr so they don't mix with the command's output. RUST_LOG overrides -v,
+// for example RUST_LOG=ilp::plugin=trace
+fn init_logger(verbosity: u64) {
+    let level = match verbosity {
+        0 => LogLevelFilter::Warn,
+        1 => LogLevelFilter::Info,
+        2 => LogLevelFilter::Debug,
+        _ => LogLevelFilter::Trace,
+    };
+    let mut builder = LogBuilder::new();
+    builder.filter(None, LogLevelFilter::Warn);
+    builder.filter(Some("ilp"), level);
+    if let Ok(spec) = env::var("RUST_LOG") {
+        builder.parse(&spec);
     }
+    builder.init().unwrap();
 }
@@ -1,12 +1,55 @@
 use ilp_packet;
 use base64;
 use rand::{Rng, OsRng};
-use ring::{hmac, digest};
-use std::io::Read;
+use ring::{hmac, digest, constant_time};
+use aes::{Aes256, BlockCipher, NewBlockCipher};
+use aes::cipher::generic_array::GenericArray;
+use ghash::{self, GHash};
+use ghash::universal_hash::{NewUniversalHash, UniversalHash};
+use byteorder::{BigEndian, ByteOrder};
+use std::str;
+use btp_packet::Error as BtpError;
+use ilpv4::read_ilp_payment;
 
-const PSK_CONDITION_STRING: &'static [u8]= b"ilp_psk_condition";
+const PSK_STATUS_LINE: &str = "PSK/1.0";
+const PSK_CONDITION_STRING: &[u8]= b"ilp_psk_condition";
+const PSK_GENERATION_STRING: &[u8] = b"ilp_psk_generation";
+const RECEIVER_ID_STRING: &[u8] = b"ilp_psk_receiver_id";
+// Not "ilp_psk_encryption", this is the string the PSK 1.0 spec and the JS implementation use
+const PSK_ENCRYPTION_STRING: &[u8] = b"ilp_key_encryption";
+const ENCRYPTION_NONE: &str = "none";
+const ENCRYPTION_AES_256_GCM: &str = "aes-256-gcm";
 
-fn get_psk_token() -> String {
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        InvalidPacket(err: ilp_packet::errors::ParseError) {
+            description(err.description())
+            from()
+        }
+        InvalidEncoding(err: BtpError) {
+            description(err.description())
+            from()
+        }
+        WrongDestination {
+            description("packet is not addressed to the expected account")
+        }
+        WrongAmount(expected: u64, received: u64) {
+            description("received amount is less than the packet amount")
+        }
+        BadHeader(descr: &'static str) {
+            description(descr)
+        }
+        UnknownNonce {
+            description("nonce is missing or is not a PSK token")
+        }
+        Decryption {
+            description("private data could not be decrypted with the shared secret")
+        }
+    }
+}
+
+pub fn get_psk_token() -> String {
     // TODO use ring SecureRandom instead
     let mut rng = OsRng::new().unwrap();
     let bytes: [u8; 16] = rng.gen();
@@ -18,23 +61,450 @@ fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
     hmac::sign(&s_key, message).as_ref().to_vec()
 }
 
-fn packet_to_preimage(shared_secret: &[u8], packet: &[u8]) -> Vec<u8> {
+pub fn packet_to_preimage(shared_secret: &[u8], packet: &[u8]) -> Vec<u8> {
     let psk_condition_key = hmac(shared_secret, PSK_CONDITION_STRING);
     hmac(&psk_condition_key, packet)
 }
 
+// Headers and data carried along with a payment. Public headers are readable by anyone who
+// sees the packet, private headers and data are meant only for the receiver
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PskMemo {
+    pub public_headers: Vec<(String, String)>,
+    pub private_headers: Vec<(String, String)>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encryption {
+    None,
+    Aes256Gcm,
+}
+
+// PSK 1.0 uses the whole 16 byte nonce as the AES-256-GCM IV. ring only takes 12 byte IVs, so
+// this is GCM put together from AES and GHASH. IVs that aren't 12 bytes are hashed into the
+// first counter block (NIST SP 800-38D section 7.1), the same as crypto.createCipheriv does
+struct Aes256Gcm {
+    cipher: Aes256,
+    ghash_key: ghash::Key,
+    first_counter: ghash::Block,
+}
+
+impl Aes256Gcm {
+    fn new(key: &[u8], iv: &[u8]) -> Aes256Gcm {
+        let cipher = Aes256::new(GenericArray::from_slice(key));
+        let mut ghash_key = GenericArray::default();
+        cipher.encrypt_block(&mut ghash_key);
+        let first_counter = ghash_with_length(&ghash_key, iv);
+        Aes256Gcm { cipher, ghash_key, first_counter }
+    }
+
+    // XORs the data with the key stream, which both encrypts and decrypts it
+    fn apply_key_stream(&self, data: &mut [u8]) {
+        let mut counter = self.first_counter;
+        for chunk in data.chunks_mut(16) {
+            // Only the last 32 bits are incremented
+            let count = BigEndian::read_u32(&counter[12..]).wrapping_add(1);
+            BigEndian::write_u32(&mut counter[12..], count);
+            let mut key_stream = counter;
+            self.cipher.encrypt_block(&mut key_stream);
+            for (byte, key_byte) in chunk.iter_mut().zip(key_stream.iter()) {
+                *byte ^= key_byte;
+            }
+        }
+    }
+
+    // PSK doesn't use additional authenticated data
+    fn tag(&self, ciphertext: &[u8]) -> [u8; 16] {
+        let hash = ghash_with_length(&self.ghash_key, ciphertext);
+        let mut mask = self.first_counter;
+        self.cipher.encrypt_block(&mut mask);
+        let mut tag = [0u8; 16];
+        for (i, byte) in tag.iter_mut().enumerate() {
+            *byte = hash[i] ^ mask[i];
+        }
+        tag
+    }
+}
+
+// GHASH of the zero-padded data followed by a block with its length in bits. With an empty first
+// half, that block is the same for the IV and for ciphertext without additional data
+fn ghash_with_length(key: &ghash::Key, data: &[u8]) -> ghash::Block {
+    let mut ghash = GHash::new(key);
+    ghash.update_padded(data);
+    let mut lengths = ghash::Block::default();
+    BigEndian::write_u64(&mut lengths[8..], data.len() as u64 * 8);
+    ghash.update(&lengths);
+    ghash.finalize().into_bytes()
+}
+
+fn encrypt(shared_secret: &[u8], nonce: &[u8], plaintext: &[u8]) -> (Vec<u8>, [u8; 16]) {
+    let gcm = Aes256Gcm::new(&hmac(shared_secret, PSK_ENCRYPTION_STRING), nonce);
+    let mut ciphertext = plaintext.to_vec();
+    gcm.apply_key_stream(&mut ciphertext);
+    let tag = gcm.tag(&ciphertext);
+    (ciphertext, tag)
+}
+
+fn decrypt(shared_secret: &[u8], nonce: &[u8], ciphertext: &[u8], tag: &[u8]) -> Result<Vec<u8>, Error> {
+    let gcm = Aes256Gcm::new(&hmac(shared_secret, PSK_ENCRYPTION_STRING), nonce);
+    constant_time::verify_slices_are_equal(&gcm.tag(ciphertext), tag)
+        .map_err(|_| Error::Decryption)?;
+    let mut plaintext = ciphertext.to_vec();
+    gcm.apply_key_stream(&mut plaintext);
+    Ok(plaintext)
+}
+
+fn header_lines(headers: &[(String, String)]) -> Result<Vec<String>, Error> {
+    headers.iter().map(|&(ref name, ref value)| {
+        if name.is_empty() || name.contains(':') || name.contains('\n') || value.contains('\n') {
+            Err(Error::BadHeader("header names cannot be empty or contain colons or newlines, values cannot contain newlines"))
+        } else {
+            Ok(format!("{}: {}", name, value))
+        }
+    }).collect()
+}
+
 pub fn create_packet_and_condition(shared_secret: &[u8], destination_account: &str, destination_amount: u64) -> (Vec<u8>, [u8; 32]) {
-    let nonce = get_psk_token();
-    // TODO support encryption and memos
-    let data = format!("PSK/1.0\nNonce: {}\nEncryption: none\n\n\n\n", nonce).into_bytes();
+    // An empty memo always serializes successfully
+    create_packet_and_condition_with_memo(shared_secret, destination_account, destination_amount, &PskMemo::default(), Encryption::None).unwrap()
+}
+
+pub fn create_packet_and_condition_with_memo(shared_secret: &[u8], destination_account: &str, destination_amount: u64, memo: &PskMemo, encryption: Encryption) -> Result<(Vec<u8>, [u8; 32]), Error> {
+    // TODO use ring SecureRandom instead
+    let nonce: [u8; 16] = OsRng::new().unwrap().gen();
+    create_packet_and_condition_with_nonce(shared_secret, destination_account, destination_amount, memo, encryption, &nonce)
+}
+
+fn create_packet_and_condition_with_nonce(shared_secret: &[u8], destination_account: &str, destination_amount: u64, memo: &PskMemo, encryption: Encryption, nonce: &[u8; 16]) -> Result<(Vec<u8>, [u8; 32]), Error> {
+    let private_lines = header_lines(&memo.private_headers)?;
+    let mut private = format!("{}\n\n", private_lines.join("\n")).into_bytes();
+    private.extend_from_slice(&memo.data);
+
+    let encryption_header = match encryption {
+        Encryption::None => ENCRYPTION_NONE.to_string(),
+        Encryption::Aes256Gcm => {
+            let (ciphertext, tag) = encrypt(shared_secret, nonce, &private);
+            private = ciphertext;
+            format!("{} {}", ENCRYPTION_AES_256_GCM, base64::encode_config(&tag, base64::URL_SAFE_NO_PAD))
+        },
+    };
+    let nonce = base64::encode_config(nonce, base64::URL_SAFE_NO_PAD);
+
+    let mut public_lines = vec![
+        PSK_STATUS_LINE.to_string(),
+        format!("Nonce: {}", nonce),
+        format!("Encryption: {}", encryption_header),
+    ];
+    public_lines.extend(header_lines(&memo.public_headers)?);
+    let mut data = format!("{}\n\n", public_lines.join("\n")).into_bytes();
+    data.extend_from_slice(&private);
     let packet = ilp_packet::packet::IlpPayment {
         account: destination_account.to_string(),
         amount: destination_amount,
         data,
-        // TODO don't use unwrap
-    }.to_bytes().unwrap();
+    }.to_bytes()?;
     let preimage = packet_to_preimage(shared_secret, &packet);
     let mut condition = [0u8; 32];
-    let mut condition_slice = digest::digest(&digest::SHA256, &preimage).as_ref().read_exact(&mut condition);
-    (packet, condition)
+    condition.copy_from_slice(digest::digest(&digest::SHA256, &preimage).as_ref());
+    Ok((packet, condition))
+}
+
+// Receivers derive a shared secret for each token they hand out so that they don't need to
+// remember them. The token is appended to the destination account so the receiver can
+// regenerate the shared secret when the payment arrives
+pub fn get_receiver_id(receiver_secret: &[u8]) -> String {
+    let receiver_id = hmac(receiver_secret, RECEIVER_ID_STRING);
+    base64::encode_config(&receiver_id[..8], base64::URL_SAFE_NO_PAD)
+}
+
+pub fn generate_shared_secret_from_token(receiver_secret: &[u8], token: &str) -> Vec<u8> {
+    let shared_secret_generator = hmac(receiver_secret, PSK_GENERATION_STRING);
+    hmac(&shared_secret_generator, token.as_bytes())[..16].to_vec()
+}
+
+fn find_end_of_block(data: &[u8]) -> Option<usize> {
+    data.windows(2).position(|window| window == b"\n\n")
+}
+
+fn parse_headers(block: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    let block = str::from_utf8(block).map_err(|_| Error::BadHeader("headers must be UTF-8"))?;
+    let mut headers = Vec::new();
+    for line in block.split('\n').filter(|line| !line.is_empty()) {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+            _ => return Err(Error::BadHeader("header lines must be of the form Name: value")),
+        }
+    }
+    Ok(headers)
+}
+
+// Header names are case insensitive
+fn get_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+        .map(|&(_, ref value)| value.as_str())
+}
+
+// Parses the PSK data carried in an ILP payment, checks the headers every PSK packet must have
+// and decrypts the private part if it is encrypted
+fn parse_psk_data(shared_secret: &[u8], data: &[u8]) -> Result<PskMemo, Error> {
+    let public_end = find_end_of_block(data).ok_or(Error::BadHeader("public headers are not terminated"))?;
+    let public_block = &data[..public_end];
+    let status_end = public_block.iter().position(|&b| b == b'\n').unwrap_or_else(|| public_block.len());
+    if &public_block[..status_end] != PSK_STATUS_LINE.as_bytes() {
+        return Err(Error::BadHeader("unsupported PSK version"));
+    }
+    let mut public_headers = parse_headers(&public_block[status_end..])?;
+
+    let nonce = get_header(&public_headers, "Nonce")
+        .and_then(|nonce| base64::decode_config(nonce, base64::URL_SAFE_NO_PAD).ok())
+        .ok_or(Error::UnknownNonce)?;
+    if nonce.len() != 16 {
+        return Err(Error::UnknownNonce);
+    }
+
+    let private = &data[public_end + 2..];
+    let private = {
+        let mut encryption = get_header(&public_headers, "Encryption")
+            .ok_or(Error::BadHeader("missing encryption header"))?
+            .split(' ');
+        match (encryption.next(), encryption.next(), encryption.next()) {
+            (Some(ENCRYPTION_NONE), None, None) => private.to_vec(),
+            (Some(ENCRYPTION_AES_256_GCM), Some(tag), None) => {
+                let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD)
+                    .map_err(|_| Error::BadHeader("authentication tag must be base64url encoded"))?;
+                decrypt(shared_secret, &nonce, private, &tag)?
+            },
+            _ => return Err(Error::BadHeader("unsupported encryption")),
+        }
+    };
+    public_headers.retain(|&(ref name, _)| {
+        !name.eq_ignore_ascii_case("Nonce") && !name.eq_ignore_ascii_case("Encryption")
+    });
+
+    let private_end = find_end_of_block(&private).ok_or(Error::BadHeader("private headers are not terminated"))?;
+    let private_headers = parse_headers(&private[..private_end])?;
+
+    Ok(PskMemo {
+        public_headers,
+        private_headers,
+        data: private[private_end + 2..].to_vec(),
+    })
+}
+
+// Returns the headers and data the sender attached to the payment
+pub fn parse_packet(shared_secret: &[u8], packet: &[u8]) -> Result<PskMemo, Error> {
+    let payment = read_ilp_payment(packet)?;
+    parse_psk_data(shared_secret, &payment.data)
+}
+
+// Checks that the PSK packet is addressed to destination_account and that the incoming transfer
+// carries at least the packet amount, then regenerates the fulfillment from the shared secret
+pub fn fulfill_packet(shared_secret: &[u8], packet: &[u8], destination_account: &str, received_amount: u64) -> Result<[u8; 32], Error> {
+    let payment = read_ilp_payment(packet)?;
+    if payment.account != destination_account {
+        return Err(Error::WrongDestination);
+    }
+    if received_amount < payment.amount {
+        return Err(Error::WrongAmount(payment.amount, received_amount));
+    }
+    parse_psk_data(shared_secret, &payment.data)?;
+
+    let preimage = packet_to_preimage(shared_secret, packet);
+    let mut fulfillment = [0u8; 32];
+    fulfillment.copy_from_slice(&preimage);
+    Ok(fulfillment)
+}
+
+#[cfg(test)]
+mod receiver {
+    use super::*;
+
+    const SHARED_SECRET: [u8; 16] = [7; 16];
+
+    fn packet_with_data(data: &str) -> Vec<u8> {
+        ilp_packet::packet::IlpPayment {
+            account: "example.bob.token".to_string(),
+            amount: 100,
+            data: data.as_bytes().to_vec(),
+        }.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn fulfills_matching_packet() {
+        let (packet, condition) = create_packet_and_condition(&SHARED_SECRET, "example.bob.token", 100);
+        let fulfillment = fulfill_packet(&SHARED_SECRET, &packet, "example.bob.token", 100).unwrap();
+        assert_eq!(digest::digest(&digest::SHA256, &fulfillment).as_ref(), &condition[..]);
+    }
+
+    #[test]
+    fn rejects_wrong_destination_and_amount() {
+        let (packet, _condition) = create_packet_and_condition(&SHARED_SECRET, "example.bob.token", 100);
+        match fulfill_packet(&SHARED_SECRET, &packet, "example.carl.token", 100) {
+            Err(Error::WrongDestination) => {},
+            other => panic!("expected WrongDestination, got {:?}", other),
+        }
+        match fulfill_packet(&SHARED_SECRET, &packet, "example.bob.token", 99) {
+            Err(Error::WrongAmount(100, 99)) => {},
+            other => panic!("expected WrongAmount, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let packet = packet_with_data("PSK/2.0\nNonce: AAAAAAAAAAAAAAAAAAAAAA\nEncryption: none\n\n\n\n");
+        match fulfill_packet(&SHARED_SECRET, &packet, "example.bob.token", 100) {
+            Err(Error::BadHeader(_)) => {},
+            other => panic!("expected BadHeader, got {:?}", other),
+        }
+        let packet = packet_with_data("PSK/1.0\nEncryption: none\n\n\n\n");
+        match fulfill_packet(&SHARED_SECRET, &packet, "example.bob.token", 100) {
+            Err(Error::UnknownNonce) => {},
+            other => panic!("expected UnknownNonce, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let packet = packet_with_data("PSK/1.0\n\n\n");
+        match fulfill_packet(&SHARED_SECRET, &packet[..packet.len() - 4], "example.bob.token", 100) {
+            Err(Error::InvalidEncoding(_)) => {},
+            other => panic!("expected InvalidEncoding, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod memo {
+    use super::*;
+
+    const SHARED_SECRET: [u8; 16] = [7; 16];
+
+    #[test]
+    fn round_trips_headers_and_data() {
+        let memo = PskMemo {
+            public_headers: vec![("Order-Id".to_string(), "1234".to_string())],
+            private_headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            data: b"thanks for the coffee\n\n".to_vec(),
+        };
+        let (packet, _condition) = create_packet_and_condition_with_memo(&SHARED_SECRET, "example.bob.token", 100, &memo, Encryption::None).unwrap();
+        assert_eq!(parse_packet(&SHARED_SECRET, &packet).unwrap(), memo);
+    }
+
+    #[test]
+    fn round_trips_empty_memo() {
+        let (packet, _condition) = create_packet_and_condition(&SHARED_SECRET, "example.bob.token", 100);
+        assert_eq!(parse_packet(&SHARED_SECRET, &packet).unwrap(), PskMemo::default());
+    }
+
+    #[test]
+    fn rejects_invalid_header_names() {
+        let memo = PskMemo {
+            public_headers: vec![("Order:Id".to_string(), "1234".to_string())],
+            ..PskMemo::default()
+        };
+        match create_packet_and_condition_with_memo(&SHARED_SECRET, "example.bob.token", 100, &memo, Encryption::None) {
+            Err(Error::BadHeader(_)) => {},
+            other => panic!("expected BadHeader, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod encryption {
+    use super::*;
+
+    const SHARED_SECRET: [u8; 16] = [7; 16];
+    const NONCE: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    fn get_memo() -> PskMemo {
+        PskMemo {
+            public_headers: vec![("Order-Id".to_string(), "1234".to_string())],
+            private_headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            data: b"secret invoice details".to_vec(),
+        }
+    }
+
+    // From Node's crypto.createCipheriv("aes-256-gcm", key, iv), which the JS implementation uses
+    #[test]
+    fn matches_node_with_16_byte_iv() {
+        let gcm = Aes256Gcm::new(&[0; 32], &[0; 16]);
+        assert_eq!(gcm.tag(&[]), [0x83, 0x12, 0x35, 0x7a, 0x21, 0x11, 0xea, 0x33, 0xb1, 0xb6, 0x7e, 0xb2, 0xfe, 0x05, 0xfe, 0x86]);
+    }
+
+    // Encrypted by the JS PSK 1.0 encrypt function with the same shared secret and nonce
+    #[test]
+    fn matches_js_implementation() {
+        let (ciphertext, tag) = encrypt(&SHARED_SECRET, &NONCE, b"Content-Type: text/plain\n\nhello");
+        assert_eq!(ciphertext, vec![38, 30, 104, 50, 69, 103, 133, 64, 100, 141, 88, 144, 11, 72, 238, 112, 152, 9, 80, 187, 43, 135, 191, 114, 101, 232, 214, 76, 167, 17, 139]);
+        assert_eq!(base64::encode_config(&tag, base64::URL_SAFE_NO_PAD), "9n325N1IbyeVBDTxWOeF_A");
+        let (ciphertext, tag) = encrypt(&SHARED_SECRET, &NONCE, &[]);
+        assert!(ciphertext.is_empty());
+        assert_eq!(base64::encode_config(&tag, base64::URL_SAFE_NO_PAD), "gLkUxzevhiD00BeK1dN9qQ");
+
+        let packet = ilp_packet::packet::IlpPayment {
+            account: "example.bob.token".to_string(),
+            amount: 100,
+            data: [
+                &b"PSK/1.0\nNonce: AAECAwQFBgcICQoLDA0ODw\nEncryption: aes-256-gcm 9n325N1IbyeVBDTxWOeF_A\n\n"[..],
+                &[38, 30, 104, 50, 69, 103, 133, 64, 100, 141, 88, 144, 11, 72, 238, 112, 152, 9, 80, 187, 43, 135, 191, 114, 101, 232, 214, 76, 167, 17, 139][..],
+            ].concat(),
+        }.to_bytes().unwrap();
+        assert_eq!(parse_packet(&SHARED_SECRET, &packet).unwrap(), PskMemo {
+            private_headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            data: b"hello".to_vec(),
+            ..PskMemo::default()
+        });
+    }
+
+    #[test]
+    fn writes_the_tag_in_the_encryption_header() {
+        let (packet, _condition) = create_packet_and_condition_with_nonce(&SHARED_SECRET, "example.bob.token", 100, &PskMemo::default(), Encryption::Aes256Gcm, &NONCE).unwrap();
+        let payment = read_ilp_payment(&packet).unwrap();
+        let (_ciphertext, tag) = encrypt(&SHARED_SECRET, &NONCE, b"\n\n");
+        let expected = format!("PSK/1.0\nNonce: AAECAwQFBgcICQoLDA0ODw\nEncryption: aes-256-gcm {}\n\n",
+            base64::encode_config(&tag, base64::URL_SAFE_NO_PAD));
+        assert!(payment.data.starts_with(expected.as_bytes()));
+    }
+
+    #[test]
+    fn round_trips_encrypted_memo() {
+        let (packet, condition) = create_packet_and_condition_with_memo(&SHARED_SECRET, "example.bob.token", 100, &get_memo(), Encryption::Aes256Gcm).unwrap();
+        let payment = read_ilp_payment(&packet).unwrap();
+        assert!(!payment.data.windows(22).any(|window| window == b"secret invoice details"));
+        assert_eq!(parse_packet(&SHARED_SECRET, &packet).unwrap(), get_memo());
+
+        let fulfillment = fulfill_packet(&SHARED_SECRET, &packet, "example.bob.token", 100).unwrap();
+        assert_eq!(digest::digest(&digest::SHA256, &fulfillment).as_ref(), &condition[..]);
+    }
+
+    #[test]
+    fn round_trips_any_length() {
+        for length in 0..50 {
+            let plaintext: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let (ciphertext, tag) = encrypt(&SHARED_SECRET, &NONCE, &plaintext);
+            assert_eq!(decrypt(&SHARED_SECRET, &NONCE, &ciphertext, &tag).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_shared_secret_and_tampering() {
+        let (packet, _condition) = create_packet_and_condition_with_memo(&SHARED_SECRET, "example.bob.token", 100, &get_memo(), Encryption::Aes256Gcm).unwrap();
+        match parse_packet(&[8; 16], &packet) {
+            Err(Error::Decryption) => {},
+            other => panic!("expected Decryption, got {:?}", other),
+        }
+        let (mut ciphertext, tag) = encrypt(&SHARED_SECRET, &NONCE, b"hello");
+        ciphertext[0] ^= 1;
+        match decrypt(&SHARED_SECRET, &NONCE, &ciphertext, &tag) {
+            Err(Error::Decryption) => {},
+            other => panic!("expected Decryption, got {:?}", other),
+        }
+        match decrypt(&SHARED_SECRET, &NONCE, &[], &tag[..8]) {
+            Err(Error::Decryption) => {},
+            other => panic!("expected Decryption, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,465 @@
+use std::io::{Cursor, Read, Write, Error as IoError};
+use std::collections::{HashMap, HashSet, VecDeque};
+use ilp_packet;
+use ilp_packet::oer::WriteOerExt;
+use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use ring::{aead, hmac, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use btp_packet::{Error as BtpError, read_var_octet_slice};
+use ilpv4::read_ilp_payment;
+
+// Name of the protocol data entry that carries the receiver's response in Fulfills and Rejects
+pub const PROTOCOL_NAME: &str = "psk2";
+const ENCRYPTION_KEY_STRING: &[u8] = b"ilp_psk2_encryption";
+const FULFILLMENT_GENERATION_STRING: &[u8] = b"ilp_psk2_fulfillment";
+const NONCE_LENGTH: usize = 12;
+const AUTH_TAG_LENGTH: usize = 16;
+const LAST_CHUNK_FLAG: u8 = 0x01;
+// Payments a PaymentTracker remembers, in progress and finished together, before it forgets the oldest
+pub const MAX_TRACKED_PAYMENTS: usize = 10_000;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: IoError) {
+            description(err.description())
+            from()
+        }
+        InvalidPacket(err: ilp_packet::errors::ParseError) {
+            description(err.description())
+            from()
+        }
+        InvalidEncoding(err: BtpError) {
+            description(err.description())
+            from()
+        }
+        Decryption {
+            description("data could not be decrypted with the shared secret")
+        }
+        Invalid(descr: &'static str) {
+            description(descr)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[repr(u8)]
+pub enum PskPacketType {
+    Request = 4,
+    Response = 5,
+    Error = 6,
+}
+
+// Sent by the sender in the data of each chunk's ILP payment
+#[derive(Debug, PartialEq, Clone)]
+pub struct PskRequest {
+    pub payment_id: [u8; 16],
+    pub sequence: u32,
+    // Minimum amount the receiver should get for this chunk
+    pub chunk_amount: u64,
+    pub last_chunk: bool,
+    pub data: Vec<u8>,
+}
+
+// Sent back by the receiver with the fulfillment (packet_type Response) or the
+// rejection (packet_type Error) of each chunk
+#[derive(Debug, PartialEq, Clone)]
+pub struct PskResponse {
+    pub packet_type: PskPacketType,
+    pub payment_id: [u8; 16],
+    pub sequence: u32,
+    // Total amount the receiver has gotten for this payment so far
+    pub amount_received: u64,
+    pub data: Vec<u8>,
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let s_key = hmac::SigningKey::new(&digest::SHA256, key);
+    hmac::sign(&s_key, message).as_ref().to_vec()
+}
+
+fn encrypt(shared_secret: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    seal(&hmac(shared_secret, ENCRYPTION_KEY_STRING), plaintext)
+}
+
+fn decrypt(shared_secret: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+    open(&hmac(shared_secret, ENCRYPTION_KEY_STRING), encrypted)
+}
+
+// Encrypts with AES-256-GCM. The result is the random nonce, followed by the auth tag,
+// followed by the ciphertext
+pub fn seal(key_bytes: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    // Keys are always derived with HMAC-SHA256, and 32 bytes is always valid for AES-256
+    let key = aead::SealingKey::new(&aead::AES_256_GCM, key_bytes).unwrap();
+    let mut nonce = [0u8; NONCE_LENGTH];
+    // TODO don't use unwrap
+    SystemRandom::new().fill(&mut nonce).unwrap();
+    let mut in_out = plaintext.to_vec();
+    in_out.extend(vec![0u8; AUTH_TAG_LENGTH]);
+    let sealed_len = aead::seal_in_place(&key, &nonce, &[], &mut in_out, AUTH_TAG_LENGTH).unwrap();
+    let tag = in_out.split_off(sealed_len - AUTH_TAG_LENGTH);
+
+    let mut encrypted = Vec::with_capacity(NONCE_LENGTH + AUTH_TAG_LENGTH + in_out.len());
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&tag);
+    encrypted.extend_from_slice(&in_out);
+    encrypted
+}
+
+pub fn open(key_bytes: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+    if encrypted.len() < NONCE_LENGTH + AUTH_TAG_LENGTH {
+        return Err(Error::Decryption);
+    }
+    let key = aead::OpeningKey::new(&aead::AES_256_GCM, key_bytes).map_err(|_| Error::Decryption)?;
+    let nonce = &encrypted[..NONCE_LENGTH];
+    let tag = &encrypted[NONCE_LENGTH..NONCE_LENGTH + AUTH_TAG_LENGTH];
+    let mut in_out = encrypted[NONCE_LENGTH + AUTH_TAG_LENGTH..].to_vec();
+    in_out.extend_from_slice(tag);
+    let plaintext_len = aead::open_in_place(&key, nonce, &[], 0, &mut in_out)
+        .map_err(|_| Error::Decryption)?
+        .len();
+    in_out.truncate(plaintext_len);
+    Ok(in_out)
+}
+
+impl PskRequest {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_u8(PskPacketType::Request as u8)?;
+        bytes.write_all(&self.payment_id)?;
+        bytes.write_u32::<BigEndian>(self.sequence)?;
+        bytes.write_u8(if self.last_chunk { LAST_CHUNK_FLAG } else { 0 })?;
+        bytes.write_u64::<BigEndian>(self.chunk_amount)?;
+        bytes.write_var_octet_string(&self.data)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<PskRequest, Error> {
+        let mut reader = Cursor::new(bytes);
+        if reader.read_u8()? != PskPacketType::Request as u8 {
+            return Err(Error::Invalid("packet is not a PSK request"));
+        }
+        let mut payment_id = [0u8; 16];
+        reader.read_exact(&mut payment_id)?;
+        let sequence = reader.read_u32::<BigEndian>()?;
+        let last_chunk = reader.read_u8()? & LAST_CHUNK_FLAG != 0;
+        let chunk_amount = reader.read_u64::<BigEndian>()?;
+        let data = read_var_octet_slice(&mut reader)?.to_vec();
+        Ok(PskRequest {
+            payment_id,
+            sequence,
+            chunk_amount,
+            last_chunk,
+            data,
+        })
+    }
+}
+
+impl PskResponse {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_u8(self.packet_type.clone() as u8)?;
+        bytes.write_all(&self.payment_id)?;
+        bytes.write_u32::<BigEndian>(self.sequence)?;
+        bytes.write_u64::<BigEndian>(self.amount_received)?;
+        bytes.write_var_octet_string(&self.data)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<PskResponse, Error> {
+        let mut reader = Cursor::new(bytes);
+        let packet_type = match reader.read_u8()? {
+            5 => PskPacketType::Response,
+            6 => PskPacketType::Error,
+            _ => return Err(Error::Invalid("packet is not a PSK response or error")),
+        };
+        let mut payment_id = [0u8; 16];
+        reader.read_exact(&mut payment_id)?;
+        let sequence = reader.read_u32::<BigEndian>()?;
+        let amount_received = reader.read_u64::<BigEndian>()?;
+        let data = read_var_octet_slice(&mut reader)?.to_vec();
+        Ok(PskResponse {
+            packet_type,
+            payment_id,
+            sequence,
+            amount_received,
+            data,
+        })
+    }
+}
+
+pub fn data_to_fulfillment(shared_secret: &[u8], data: &[u8]) -> [u8; 32] {
+    let fulfillment_key = hmac(shared_secret, FULFILLMENT_GENERATION_STRING);
+    let mut fulfillment = [0u8; 32];
+    fulfillment.copy_from_slice(&hmac(&fulfillment_key, data));
+    fulfillment
+}
+
+// Returns the ILP packet for one chunk and the condition for its transfer
+pub fn create_request(shared_secret: &[u8], destination_account: &str, request: &PskRequest) -> Result<(Vec<u8>, [u8; 32]), Error> {
+    let data = encrypt(shared_secret, &request.to_bytes()?);
+    let fulfillment = data_to_fulfillment(shared_secret, &data);
+    let packet = ilp_packet::packet::IlpPayment {
+        account: destination_account.to_string(),
+        amount: request.chunk_amount,
+        data,
+    }.to_bytes()?;
+    let mut condition = [0u8; 32];
+    condition.copy_from_slice(digest::digest(&digest::SHA256, &fulfillment).as_ref());
+    Ok((packet, condition))
+}
+
+pub fn parse_request(shared_secret: &[u8], packet: &[u8]) -> Result<PskRequest, Error> {
+    let payment = read_ilp_payment(packet)?;
+    PskRequest::from_bytes(&decrypt(shared_secret, &payment.data)?)
+}
+
+pub fn create_response(shared_secret: &[u8], response: &PskResponse) -> Result<Vec<u8>, Error> {
+    Ok(encrypt(shared_secret, &response.to_bytes()?))
+}
+
+pub fn parse_response(shared_secret: &[u8], data: &[u8]) -> Result<PskResponse, Error> {
+    PskResponse::from_bytes(&decrypt(shared_secret, data)?)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChunkResult {
+    // Fulfill the chunk's transfer and send the encrypted response back with it
+    Fulfill {
+        fulfillment: [u8; 32],
+        response: Vec<u8>,
+    },
+    // Reject the chunk's transfer and send the encrypted error back with it
+    Reject {
+        response: Vec<u8>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ReceivedPayment {
+    pub amount_received: u64,
+    pub chunks_received: u32,
+    // Sequence numbers of the chunks we fulfilled, so a retried chunk isn't counted twice
+    sequences: HashSet<u32>,
+}
+
+// Keeps track of how much has arrived for each chunked payment. Once the last chunk arrives
+// only the final amount is kept, so later chunks for the payment are still rejected
+#[derive(Debug)]
+pub struct PaymentTracker {
+    payments: HashMap<[u8; 16], ReceivedPayment>,
+    finished: HashMap<[u8; 16], u64>,
+    // Payment ids in the order their first chunk arrived, for forgetting the oldest
+    order: VecDeque<[u8; 16]>,
+    max_payments: usize,
+}
+
+impl Default for PaymentTracker {
+    fn default() -> Self {
+        PaymentTracker {
+            payments: HashMap::new(),
+            finished: HashMap::new(),
+            order: VecDeque::new(),
+            max_payments: MAX_TRACKED_PAYMENTS,
+        }
+    }
+}
+
+impl PaymentTracker {
+    pub fn new() -> Self {
+        PaymentTracker::default()
+    }
+
+    // At least one payment is always kept
+    pub fn with_max_payments(mut self, max_payments: usize) -> Self {
+        self.max_payments = max_payments.max(1);
+        self
+    }
+
+    // Payments in progress
+    pub fn get_payment(&self, payment_id: &[u8; 16]) -> Option<&ReceivedPayment> {
+        self.payments.get(payment_id)
+    }
+
+    // The total amount received, if the last chunk of the payment has arrived
+    pub fn get_finished_amount(&self, payment_id: &[u8; 16]) -> Option<u64> {
+        self.finished.get(payment_id).cloned()
+    }
+
+    // Decides whether to fulfill an incoming chunk of transfer_amount carrying the given ILP packet
+    pub fn handle_chunk(&mut self, shared_secret: &[u8], transfer_amount: u64, packet: &[u8]) -> Result<ChunkResult, Error> {
+        let payment = read_ilp_payment(packet)?;
+        let request = PskRequest::from_bytes(&decrypt(shared_secret, &payment.data)?)?;
+
+        let (accept, amount_received) = match self.finished.get(&request.payment_id) {
+            Some(&amount_received) => (false, amount_received),
+            None => {
+                if !self.payments.contains_key(&request.payment_id) {
+                    self.track(request.payment_id);
+                }
+                let received = self.payments.entry(request.payment_id).or_insert_with(ReceivedPayment::default);
+                let new_total = if received.sequences.contains(&request.sequence) || transfer_amount < request.chunk_amount {
+                    None
+                } else {
+                    received.amount_received.checked_add(transfer_amount)
+                };
+                if let Some(new_total) = new_total {
+                    received.amount_received = new_total;
+                    received.chunks_received += 1;
+                    received.sequences.insert(request.sequence);
+                }
+                (new_total.is_some(), received.amount_received)
+            },
+        };
+        if accept && request.last_chunk {
+            self.payments.remove(&request.payment_id);
+            self.finished.insert(request.payment_id, amount_received);
+        }
+
+        let response = create_response(shared_secret, &PskResponse {
+            packet_type: if accept { PskPacketType::Response } else { PskPacketType::Error },
+            payment_id: request.payment_id,
+            sequence: request.sequence,
+            amount_received,
+            data: Vec::new(),
+        })?;
+        if accept {
+            Ok(ChunkResult::Fulfill {
+                fulfillment: data_to_fulfillment(shared_secret, &payment.data),
+                response,
+            })
+        } else {
+            Ok(ChunkResult::Reject {
+                response,
+            })
+        }
+    }
+
+    // Anyone who can send us chunks can make up payment ids, so the maps can't grow forever
+    fn track(&mut self, payment_id: [u8; 16]) {
+        self.order.push_back(payment_id);
+        while self.order.len() > self.max_payments {
+            if let Some(oldest) = self.order.pop_front() {
+                self.payments.remove(&oldest);
+                self.finished.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod chunks {
+    use super::*;
+
+    const SHARED_SECRET: [u8; 32] = [9; 32];
+
+    fn get_request(sequence: u32, chunk_amount: u64, last_chunk: bool) -> PskRequest {
+        PskRequest {
+            payment_id: [1; 16],
+            sequence,
+            chunk_amount,
+            last_chunk,
+            data: b"hello".to_vec(),
+        }
+    }
+
+    #[test]
+    fn round_trips_request() {
+        let request = get_request(3, 100, true);
+        let (packet, _condition) = create_request(&SHARED_SECRET, "example.bob", &request).unwrap();
+        assert_eq!(parse_request(&SHARED_SECRET, &packet).unwrap(), request);
+        match parse_request(&[0; 32], &packet) {
+            Err(Error::Decryption) => {},
+            other => panic!("expected Decryption, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn acknowledges_chunks_with_amount_received() {
+        let mut tracker = PaymentTracker::new();
+        for sequence in 0..3 {
+            let request = get_request(sequence, 10, sequence == 2);
+            let (packet, condition) = create_request(&SHARED_SECRET, "example.bob", &request).unwrap();
+            match tracker.handle_chunk(&SHARED_SECRET, 12, &packet).unwrap() {
+                ChunkResult::Fulfill { fulfillment, response } => {
+                    assert_eq!(digest::digest(&digest::SHA256, &fulfillment).as_ref(), &condition[..]);
+                    let response = parse_response(&SHARED_SECRET, &response).unwrap();
+                    assert_eq!(response.packet_type, PskPacketType::Response);
+                    assert_eq!(response.sequence, sequence);
+                    assert_eq!(response.amount_received, 12 * (sequence as u64 + 1));
+                },
+                other => panic!("expected fulfill, got {:?}", other),
+            }
+        }
+        assert!(tracker.get_payment(&[1; 16]).is_none());
+        assert_eq!(tracker.get_finished_amount(&[1; 16]), Some(36));
+
+        // Chunks after the last one are rejected
+        let (packet, _condition) = create_request(&SHARED_SECRET, "example.bob", &get_request(3, 10, false)).unwrap();
+        match tracker.handle_chunk(&SHARED_SECRET, 12, &packet).unwrap() {
+            ChunkResult::Reject { response } => {
+                let response = parse_response(&SHARED_SECRET, &response).unwrap();
+                assert_eq!(response.packet_type, PskPacketType::Error);
+                assert_eq!(response.amount_received, 36);
+            },
+            other => panic!("expected reject, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_chunks_below_minimum_amount() {
+        let mut tracker = PaymentTracker::new();
+        let (packet, _condition) = create_request(&SHARED_SECRET, "example.bob", &get_request(0, 10, false)).unwrap();
+        match tracker.handle_chunk(&SHARED_SECRET, 9, &packet).unwrap() {
+            ChunkResult::Reject { .. } => {},
+            other => panic!("expected reject, got {:?}", other),
+        }
+        assert_eq!(tracker.get_payment(&[1; 16]).unwrap().amount_received, 0);
+    }
+
+    #[test]
+    fn rejects_repeated_and_overflowing_chunks() {
+        let mut tracker = PaymentTracker::new();
+        let (packet, _condition) = create_request(&SHARED_SECRET, "example.bob", &get_request(0, 10, false)).unwrap();
+        match tracker.handle_chunk(&SHARED_SECRET, u64::max_value(), &packet).unwrap() {
+            ChunkResult::Fulfill { .. } => {},
+            other => panic!("expected fulfill, got {:?}", other),
+        }
+        // The same chunk again is rejected rather than counted twice
+        match tracker.handle_chunk(&SHARED_SECRET, 10, &packet).unwrap() {
+            ChunkResult::Reject { .. } => {},
+            other => panic!("expected reject, got {:?}", other),
+        }
+        let (packet, _condition) = create_request(&SHARED_SECRET, "example.bob", &get_request(1, 10, false)).unwrap();
+        match tracker.handle_chunk(&SHARED_SECRET, 10, &packet).unwrap() {
+            ChunkResult::Reject { .. } => {},
+            other => panic!("expected reject, got {:?}", other),
+        }
+        assert_eq!(tracker.get_payment(&[1; 16]).unwrap().amount_received, u64::max_value());
+    }
+
+    #[test]
+    fn forgets_the_oldest_payments() {
+        let mut tracker = PaymentTracker::new().with_max_payments(2);
+        for id in 1..4 {
+            let request = PskRequest {
+                payment_id: [id; 16],
+                ..get_request(0, 10, id == 3)
+            };
+            let (packet, _condition) = create_request(&SHARED_SECRET, "example.bob", &request).unwrap();
+            tracker.handle_chunk(&SHARED_SECRET, 10, &packet).unwrap();
+        }
+        assert!(tracker.get_payment(&[1; 16]).is_none());
+        assert_eq!(tracker.get_payment(&[2; 16]).unwrap().amount_received, 10);
+        assert_eq!(tracker.get_finished_amount(&[3; 16]), Some(10));
+    }
+
+    #[test]
+    fn rejects_truncated_requests() {
+        let mut bytes = get_request(0, 10, false).to_bytes().unwrap();
+        let length = bytes.len();
+        // Claims more data than there is
+        bytes[length - 6] = 0xff;
+        assert!(PskRequest::from_bytes(&bytes).is_err());
+    }
+}
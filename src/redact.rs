@@ -0,0 +1,42 @@
+// Hides secrets like shared secrets and fulfillments in log messages
+use std::fmt;
+
+pub struct Redacted<'a, T: 'a + fmt::Debug + ?Sized> {
+    value: &'a T,
+    show: bool,
+}
+
+impl<'a, T: 'a + fmt::Debug + ?Sized> Redacted<'a, T> {
+    pub fn new(value: &'a T, show: bool) -> Self {
+        Redacted { value, show }
+    }
+}
+
+impl<'a, T: 'a + fmt::Debug + ?Sized> fmt::Debug for Redacted<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.show {
+            self.value.fmt(f)
+        } else {
+            f.write_str("[redacted]")
+        }
+    }
+}
+
+// Only shows the value if trace logging is enabled for the module doing the logging
+macro_rules! redacted {
+    ($value:expr) => (
+        ::redact::Redacted::new(&$value, log_enabled!(::log::LogLevel::Trace))
+    )
+}
+
+#[cfg(test)]
+mod redacting {
+    use super::*;
+
+    #[test]
+    fn hides_value_unless_shown() {
+        let secret = [1u8, 2, 3];
+        assert_eq!(format!("{:?}", Redacted::new(&secret, false)), "[redacted]");
+        assert_eq!(format!("{:?}", Redacted::new(&secret, true)), "[1, 2, 3]");
+    }
+}
@@ -4,8 +4,8 @@ use psk;
 use base64;
 use plugin;
 use plugin::{Transfer, Plugin};
-use serde_json;
-use uuid::{Uuid, UuidVersion};
+use psk::{PskMemo, Encryption};
+use uuid::Uuid;
 use chrono::prelude::*;
 use chrono::Duration;
 
@@ -24,34 +24,88 @@ quick_error! {
             description(err.description())
             from()
         }
+        Psk(err: psk::Error) {
+            description(err.description())
+            from()
+        }
+        InvalidSharedSecret(err: base64::DecodeError) {
+            description("receiver's shared secret is not base64url")
+            display("receiver's shared secret is not base64url: {}", err)
+        }
+        InvalidHoldDuration(seconds: i64) {
+            description("hold duration must be a positive number of seconds")
+            display("hold duration must be a positive number of seconds, not {}", seconds)
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct LedgerInfo {
-    currency_code: String,
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LedgerInfo {
+    pub currency_code: String,
     // TODO can scale be negative?
-    currency_scale: i32,
+    pub currency_scale: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReceiverInfo {
+    pub name: String,
+    pub image_url: String,
+    pub identifier: String,
 }
 
-    #[derive(Debug, Deserialize)]
-struct ReceiverInfo {
-    name: String,
-    image_url: String,
-    identifier: String,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpspReceiver {
+    pub destination_account: String,
+    pub shared_secret: String,
+    pub maximum_destination_amount: String,
+    pub minimum_destination_amount: String,
+    pub ledger_info: LedgerInfo,
+    pub receiver_info: ReceiverInfo,
 }
 
-#[derive(Debug, Deserialize)]
-struct SpspReceiver {
-    destination_account: String,
-    shared_secret: String,
-    maximum_destination_amount: String,
-    minimum_destination_amount: String,
-    ledger_info: LedgerInfo,
-    receiver_info: ReceiverInfo,
+// Proof of a completed payment, for reconciling against the receiver's records
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct PaymentReceipt {
+    pub transfer_id: Uuid,
+    // The SPSP endpoint that was paid and the identifier it gave for itself
+    pub receiver: String,
+    pub receiver_identifier: String,
+    pub destination_account: String,
+    // In the source ledger's units
+    pub source_amount: u64,
+    // In the receiver's ledger units
+    pub destination_amount: u64,
+    #[serde(serialize_with = "plugin::as_base64")]
+    pub condition: [u8; 32],
+    #[serde(serialize_with = "plugin::as_base64")]
+    pub fulfillment: [u8; 32],
+    // RFC 3339 timestamps
+    pub sent_at: String,
+    pub fulfilled_at: String,
+    pub connector: String,
+}
+
+pub struct PayOptions {
+    pub memo: PskMemo,
+    // Decimal places of the source account's currency
+    pub source_scale: i32,
+    // Seconds the outgoing transfer is held for
+    pub hold_duration: i64,
+}
+
+impl Default for PayOptions {
+    fn default() -> Self {
+        PayOptions {
+            memo: PskMemo::default(),
+            // TODO get scale from plugin
+            source_scale: 6,
+            // TODO make hold duration depend on quote
+            hold_duration: 60,
+        }
+    }
 }
 
-fn query(receiver: &str) -> Result<SpspReceiver, Error> {
+pub fn query(receiver: &str) -> Result<SpspReceiver, Error> {
     // TODO actually use webfinger
     let resp = &mut reqwest::get(receiver)?;
     // TODO what if the response doesn't match?
@@ -67,60 +121,194 @@ fn int_to_float(amount: u64, scale: i32) -> f64 {
     amount as f64 * 10.0_f64.powi(0 - scale)
 }
 
+// None if the hold duration isn't positive or the expiry doesn't fit in a date
+// (Duration::seconds panics past i64::MAX milliseconds)
+fn expiry_after(hold_duration: i64) -> Option<DateTime<Utc>> {
+    if hold_duration <= 0 || hold_duration > i64::max_value() / 1000 {
+        return None;
+    }
+    Utc::now().checked_add_signed(Duration::seconds(hold_duration))
+}
+
+// Amounts are integers in each ledger's units, so divide by 10^scale for the decimal amount
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Quote {
+    pub source_amount: u64,
+    pub source_scale: i32,
+    pub destination_amount: u64,
+    pub destination_scale: i32,
+    pub destination_currency: String,
+}
+
+impl Quote {
+    pub fn source_amount_decimal(&self) -> f64 {
+        int_to_float(self.source_amount, self.source_scale)
+    }
+
+    pub fn destination_amount_decimal(&self) -> f64 {
+        int_to_float(self.destination_amount, self.destination_scale)
+    }
+}
+
 pub fn quote_source(btp_server: &str, receiver: &str, source_amount: f64) -> Result<f64, Error> {
-    let spsp_details = query(receiver)?;
-    let destination_account = spsp_details.destination_account;
     // TODO shift by scale from ledger plugin
     let source_scale = 1;
+    Ok(quote_by_source(btp_server, receiver, source_amount, source_scale)?.destination_amount_decimal())
+}
+
+pub fn quote_destination(btp_server: &str, receiver: &str, destination_amount: f64) -> Result<f64, Error> {
+    // TODO shift by scale from ledger plugin
+    let source_scale = 1;
+    Ok(quote_by_destination(btp_server, receiver, destination_amount, source_scale)?.source_amount_decimal())
+}
+
+// TODO ask the connector over btp_server once ILQP quotes are sent
+pub fn quote_by_source(_btp_server: &str, receiver: &str, source_amount: f64, source_scale: i32) -> Result<Quote, Error> {
+    let spsp_details = query(receiver)?;
+    let destination_account = spsp_details.destination_account;
     let source_amount = float_to_int(source_amount, source_scale);
     let destination_hold_duration = 10000;
     let destination_amount = ilqp::quote_source(&destination_account, source_amount, destination_hold_duration)?;
-    let destination_amount = int_to_float(destination_amount, spsp_details.ledger_info.currency_scale);
-    Ok(destination_amount)
+    Ok(Quote {
+        source_amount,
+        source_scale,
+        destination_amount,
+        destination_scale: spsp_details.ledger_info.currency_scale,
+        destination_currency: spsp_details.ledger_info.currency_code,
+    })
 }
 
-pub fn quote_destination(btp_server: &str, receiver: &str, destination_amount: f64) -> Result<f64, Error> {
+pub fn quote_by_destination(_btp_server: &str, receiver: &str, destination_amount: f64, source_scale: i32) -> Result<Quote, Error> {
     let spsp_details = query(receiver)?;
     let destination_account = spsp_details.destination_account;
     let destination_amount = float_to_int(destination_amount, spsp_details.ledger_info.currency_scale);
     let destination_hold_duration = 10000;
     let source_amount = ilqp::quote_destination(&destination_account, destination_amount, destination_hold_duration)?;
-    // TODO shift by scale from ledger plugin
-    let source_scale = 1;
-    Ok(int_to_float(source_amount, source_scale))
+    Ok(Quote {
+        source_amount,
+        source_scale,
+        destination_amount,
+        destination_scale: spsp_details.ledger_info.currency_scale,
+        destination_currency: spsp_details.ledger_info.currency_code,
+    })
+}
+
+pub fn pay(btp_server: &str, receiver: &str, source_amount: f64, destination_amount: f64) -> Result<PaymentReceipt, Error> {
+    pay_with_memo(btp_server, receiver, source_amount, destination_amount, &PskMemo::default())
 }
 
-pub fn pay(btp_server: &str, receiver: &str, source_amount: f64, destination_amount: f64) -> Result<(), Error> {
-    println!("Send payment to {} with source amount {} and destination amount {}", receiver, source_amount, destination_amount);
+pub fn pay_with_memo(btp_server: &str, receiver: &str, source_amount: f64, destination_amount: f64, memo: &PskMemo) -> Result<PaymentReceipt, Error> {
+    pay_with_options(btp_server, receiver, source_amount, destination_amount, &PayOptions {
+        memo: memo.clone(),
+        ..PayOptions::default()
+    })
+}
+
+pub fn pay_with_options(btp_server: &str, receiver: &str, source_amount: f64, destination_amount: f64, options: &PayOptions) -> Result<PaymentReceipt, Error> {
+    info!("Sending payment to {} with source amount {} and destination amount {}", receiver, source_amount, destination_amount);
+    let expires_at = expiry_after(options.hold_duration)
+        .ok_or(Error::InvalidHoldDuration(options.hold_duration))?;
     let spsp_details = query(receiver)?;
-    println!("Got receiver details: {:?}", spsp_details);
-    let shared_secret = base64::decode_config(&spsp_details.shared_secret, base64::URL_SAFE_NO_PAD).unwrap();
+    debug!("Got receiver details: destination_account={} ledger_info={:?} receiver_info={:?} shared_secret={:?}",
+        spsp_details.destination_account,
+        spsp_details.ledger_info,
+        spsp_details.receiver_info,
+        redacted!(spsp_details.shared_secret));
+    let shared_secret = base64::decode_config(&spsp_details.shared_secret, base64::URL_SAFE_NO_PAD)
+        .map_err(Error::InvalidSharedSecret)?;
     let destination_amount = float_to_int(destination_amount, spsp_details.ledger_info.currency_scale);
-    let (packet, condition) = psk::create_packet_and_condition(
+    let (packet, condition) = psk::create_packet_and_condition_with_memo(
         &shared_secret,
         &spsp_details.destination_account,
-        destination_amount);
-    println!("Created packet: {:?} and condition: {:?}", packet, condition);
+        destination_amount,
+        &options.memo,
+        Encryption::Aes256Gcm)?;
+    trace!("Created packet: {:?} and condition: {:?}", packet, condition);
 
-    // TODO get scale from plugin
-    let source_scale = 6;
-    // TODO make hold duration depend on quote
-    let source_hold_duration = 60;
+    let transfer_id = Uuid::new_v4();
+    let source_amount = float_to_int(source_amount, options.source_scale);
     let transfer = Transfer {
-        id: *Uuid::new_v4().as_bytes(),
+        id: *transfer_id.as_bytes(),
         //from: "".to_string(),
         //to: spsp_details.destination_account.to_string(),
         //ledger: "".to_string(),
-        amount: float_to_int(source_amount, source_scale),
+        amount: source_amount,
         ilp: packet,
         execution_condition: condition,
-        expires_at: Utc::now().checked_add_signed(Duration::seconds(source_hold_duration)).unwrap().to_rfc3339()
+        expires_at: expires_at.to_rfc3339(),
     };
-    println!("Sending transfer: {}", serde_json::to_string(&transfer).unwrap());
+    // The ILP packet carries the memo, so only log what identifies the transfer
+    debug!("Sending transfer: id={} amount={} destination={}", transfer_id, transfer.amount, spsp_details.destination_account);
+
+    let mut plugin = Plugin::new(btp_server)?;
+    let sent_at = Utc::now();
+    // The plugin refuses fulfillments that don't match the condition, so this is proof of payment
+    let fulfillment = plugin.prepare_and_wait_for_fulfill_sync(transfer)?;
+    Ok(PaymentReceipt {
+        transfer_id,
+        receiver: receiver.to_string(),
+        receiver_identifier: spsp_details.receiver_info.identifier,
+        destination_account: spsp_details.destination_account,
+        source_amount,
+        destination_amount,
+        condition,
+        fulfillment,
+        sent_at: sent_at.to_rfc3339(),
+        fulfilled_at: Utc::now().to_rfc3339(),
+        connector: plugin.server().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod hold_duration {
+    use super::*;
+
+    #[test]
+    fn rejects_out_of_range_hold_durations() {
+        assert!(expiry_after(30).unwrap() > Utc::now());
+        assert!(expiry_after(0).is_none());
+        assert!(expiry_after(-5).is_none());
+        assert!(expiry_after(i64::max_value()).is_none());
+        assert!(expiry_after(i64::max_value() / 1000).is_none());
+    }
 
-    // TODO don't hardcode websocket uri
-    let mut plugin = Plugin::new(btp_server).unwrap();
-    plugin.prepare_and_wait_for_fulfill_sync(transfer)
-        .map(|fulfillment| ())
-        .map_err(|err| Error::from(err))
+    #[test]
+    fn checks_hold_duration_before_paying() {
+        let options = PayOptions {
+            hold_duration: -1,
+            ..PayOptions::default()
+        };
+        match pay_with_options("btp+ws://localhost:1", "http://localhost:1", 1.0, 1.0, &options) {
+            Err(Error::InvalidHoldDuration(-1)) => {},
+            other => panic!("expected invalid hold duration, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod receipt {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn serializes_to_json() {
+        let receipt = PaymentReceipt {
+            transfer_id: Uuid::parse_str("6d9c3ed8-4d26-4f43-8d7b-8f6ac6e2a9c1").unwrap(),
+            receiver: "http://localhost:3000/bob".to_string(),
+            receiver_identifier: "bob@example.com".to_string(),
+            destination_account: "example.bob.abc".to_string(),
+            source_amount: 1000,
+            destination_amount: 10,
+            condition: [1; 32],
+            fulfillment: [2; 32],
+            sent_at: "2017-12-23T01:02:03+00:00".to_string(),
+            fulfilled_at: "2017-12-23T01:02:04+00:00".to_string(),
+            connector: "ws://localhost:8080".to_string(),
+        };
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&receipt).unwrap()).unwrap();
+        assert_eq!(json["transfer_id"], "6d9c3ed8-4d26-4f43-8d7b-8f6ac6e2a9c1");
+        assert_eq!(json["source_amount"], 1000);
+        assert_eq!(json["fulfillment"], base64::encode_config(&[2; 32], base64::URL_SAFE_NO_PAD));
+        assert_eq!(json["connector"], "ws://localhost:8080");
+    }
 }
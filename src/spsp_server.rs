@@ -0,0 +1,385 @@
+use std::io::Error as IoError;
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use hyper;
+use hyper::{Method, StatusCode};
+use hyper::header::ContentType;
+use hyper::server::{Http, Request, Response, Service};
+use futures::future;
+use ring::digest;
+use chrono::{DateTime, Utc};
+use rand::{Rng, OsRng};
+use serde_json;
+use base64;
+use psk;
+use plugin;
+use plugin::{Plugin, Transfer, TransferResult, BtpVersion};
+use ilpv4::read_ilp_payment;
+use spsp::{LedgerInfo, ReceiverInfo, SpspReceiver};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: IoError) {
+            description(err.description())
+            from()
+        }
+        Hyper(err: hyper::Error) {
+            description(err.description())
+            from()
+        }
+        Plugin(err: plugin::Error) {
+            description(err.description())
+            from()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReceivedPayment {
+    // None over BTP 2.0, which has no transfer ids
+    pub transfer_id: Option<[u8; 16]>,
+    pub amount: u64,
+    pub destination_account: String,
+    pub memo: psk::PskMemo,
+    pub fulfillment: [u8; 32],
+}
+
+pub struct SpspServer {
+    ilp_address: String,
+    receiver_secret: [u8; 32],
+    ledger_info: LedgerInfo,
+    receivers: HashMap<String, ReceiverInfo>,
+}
+
+impl SpspServer {
+    // ilp_address is the address of the account the plugin is connected to
+    pub fn new(ilp_address: &str, ledger_info: LedgerInfo) -> Self {
+        // TODO use ring SecureRandom instead
+        let mut rng = OsRng::new().unwrap();
+        SpspServer {
+            ilp_address: ilp_address.to_string(),
+            receiver_secret: rng.gen(),
+            ledger_info,
+            receivers: HashMap::new(),
+        }
+    }
+
+    // Receivers are served at /<name>
+    pub fn add_receiver(&mut self, receiver_info: ReceiverInfo) {
+        self.receivers.insert(receiver_info.name.to_string(), receiver_info);
+    }
+
+    fn account_prefix(&self) -> String {
+        format!("{}.{}.", self.ilp_address, psk::get_receiver_id(&self.receiver_secret))
+    }
+
+    // Each query gets a fresh token, and with it a fresh shared secret
+    pub fn spsp_details(&self, name: &str) -> Option<SpspReceiver> {
+        let receiver_info = match self.receivers.get(name) {
+            Some(receiver_info) => receiver_info,
+            None => return None,
+        };
+        let token = psk::get_psk_token();
+        let shared_secret = psk::generate_shared_secret_from_token(&self.receiver_secret, &token);
+        Some(SpspReceiver {
+            destination_account: format!("{}{}", self.account_prefix(), token),
+            shared_secret: base64::encode_config(&shared_secret, base64::URL_SAFE_NO_PAD),
+            maximum_destination_amount: u64::max_value().to_string(),
+            minimum_destination_amount: "0".to_string(),
+            ledger_info: self.ledger_info.clone(),
+            receiver_info: receiver_info.clone(),
+        })
+    }
+
+    // Returns the fulfillment if the transfer carries a PSK payment for one of our tokens
+    pub fn handle_prepare(&self, transfer: &Transfer) -> Option<[u8; 32]> {
+        self.receive(transfer).map(|payment| payment.fulfillment)
+    }
+
+    // Like handle_prepare, but also returns the memo the sender attached
+    pub fn receive(&self, transfer: &Transfer) -> Option<ReceivedPayment> {
+        match DateTime::parse_from_rfc3339(&transfer.expires_at) {
+            Ok(expires_at) if expires_at.with_timezone(&Utc) > Utc::now() => {},
+            _ => {
+                info!("rejecting incoming transfer that expired at {}", transfer.expires_at);
+                return None;
+            },
+        }
+        let payment = match read_ilp_payment(&transfer.ilp) {
+            Ok(payment) => payment,
+            Err(_) => return None,
+        };
+        let prefix = self.account_prefix();
+        if !payment.account.starts_with(&prefix) {
+            return None;
+        }
+        let token = &payment.account[prefix.len()..];
+        let shared_secret = psk::generate_shared_secret_from_token(&self.receiver_secret, token);
+        let fulfillment = match psk::fulfill_packet(&shared_secret, &transfer.ilp, &payment.account, transfer.amount) {
+            Ok(fulfillment) => fulfillment,
+            Err(err) => {
+                info!("rejecting incoming transfer: {:?}", err);
+                return None;
+            },
+        };
+        if digest::digest(&digest::SHA256, &fulfillment).as_ref() != &transfer.execution_condition[..] {
+            return None;
+        }
+        // fulfill_packet already checked that the memo parses
+        let memo = psk::parse_packet(&shared_secret, &transfer.ilp).unwrap_or_default();
+        Some(ReceivedPayment {
+            transfer_id: Some(transfer.id),
+            amount: transfer.amount,
+            destination_account: payment.account.to_string(),
+            memo,
+            fulfillment,
+        })
+    }
+
+    // Serves the SPSP endpoints and fulfills incoming payments until the HTTP server stops
+    pub fn run(self, listen: &SocketAddr, plugin: Plugin) -> Result<(), Error> {
+        self.run_with_handler(listen, plugin, |_payment| {})
+    }
+
+    // Calls on_payment for every payment once its Fulfill has been sent
+    pub fn run_with_handler<F>(self, listen: &SocketAddr, mut plugin: Plugin, mut on_payment: F) -> Result<(), Error>
+        where F: FnMut(&ReceivedPayment) + Send + 'static
+    {
+        let server = Arc::new(self);
+        let receiver = server.clone();
+        thread::spawn(move || {
+            // Held until the Fulfill is sent, so payments that fail to go out aren't reported
+            let fulfilled = RefCell::new(None);
+            let result = plugin.listen_for_prepares_with_callback(|transfer| {
+                match receiver.receive(transfer) {
+                    Some(payment) => {
+                        let fulfillment = payment.fulfillment;
+                        *fulfilled.borrow_mut() = Some(payment);
+                        TransferResult::Fulfilled {
+                            fulfillment,
+                            protocol_data: Vec::new(),
+                        }
+                    },
+                    None => TransferResult::Rejected {
+                        ilp_error: None,
+                        protocol_data: Vec::new(),
+                    },
+                }
+            }, |_transfer, version| {
+                if let Some(mut payment) = fulfilled.borrow_mut().take() {
+                    if version == BtpVersion::V2 {
+                        payment.transfer_id = None;
+                    }
+                    on_payment(&payment);
+                }
+            });
+            if let Err(err) = result {
+                error!("stopped listening for incoming payments: {:?}", err);
+            }
+        });
+        let http_server = Http::new().bind(listen, move || Ok(SpspService {
+            server: server.clone(),
+        }))?;
+        http_server.run()?;
+        Ok(())
+    }
+}
+
+struct SpspService {
+    server: Arc<SpspServer>,
+}
+
+impl Service for SpspService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = future::FutureResult<Response, hyper::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        if req.method() != &Method::Get {
+            return future::ok(Response::new().with_status(StatusCode::MethodNotAllowed));
+        }
+        let name = req.path().trim_start_matches('/');
+        let response = match self.server.spsp_details(name) {
+            Some(details) => match serde_json::to_string(&details) {
+                Ok(body) => Response::new()
+                    .with_header(ContentType::json())
+                    .with_body(body),
+                Err(_) => Response::new().with_status(StatusCode::InternalServerError),
+            },
+            None => Response::new().with_status(StatusCode::NotFound),
+        };
+        future::ok(response)
+    }
+}
+
+#[cfg(test)]
+mod end_to_end {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration as StdDuration;
+    use chrono::{Utc, Duration};
+    use websocket::OwnedMessage;
+    use websocket::sync::{Client, Server as WsServer};
+    use btp_packet::{BtpPacket, PacketType, PacketContents, Prepare, Response, ProtocolData, Serializable};
+    use spsp;
+
+    fn free_address() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn query_with_retries(receiver: &str) -> SpspReceiver {
+        for _i in 0..50 {
+            if let Ok(details) = spsp::query(receiver) {
+                return details;
+            }
+            thread::sleep(StdDuration::from_millis(100));
+        }
+        panic!("SPSP server did not respond");
+    }
+
+    fn send_prepare<S: ::std::io::Read + ::std::io::Write>(connection: &mut Client<S>, transfer_id: [u8; 16], amount: u64, condition: [u8; 32], ilp: Vec<u8>) {
+        let prepare = BtpPacket {
+            packet_type: PacketType::Prepare,
+            request_id: 1,
+            data: PacketContents::Prepare(Prepare {
+                transfer_id,
+                amount,
+                execution_condition: condition,
+                expires_at: Utc::now().checked_add_signed(Duration::seconds(30)).unwrap(),
+                protocol_data: vec![ProtocolData::ilp(ilp)],
+            }),
+        };
+        connection.send_message(&OwnedMessage::Binary(prepare.to_bytes().unwrap())).unwrap();
+    }
+
+    // Skips the Response to the Prepare and returns the Fulfill or Reject
+    fn next_result<S: ::std::io::Read + ::std::io::Write>(connection: &mut Client<S>) -> PacketContents {
+        loop {
+            if let OwnedMessage::Binary(bytes) = connection.recv_message().unwrap() {
+                let packet = BtpPacket::from_bytes(&bytes).unwrap();
+                match packet.data {
+                    PacketContents::Response(_) => continue,
+                    contents => return contents,
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fulfills_psk_payments() {
+        let mut btp_server = WsServer::bind("127.0.0.1:0").unwrap();
+        let btp_address = btp_server.local_addr().unwrap();
+        let http_address = free_address();
+
+        let mut spsp_server = SpspServer::new("example.bob", LedgerInfo {
+            currency_code: "USD".to_string(),
+            currency_scale: 2,
+        });
+        spsp_server.add_receiver(ReceiverInfo {
+            name: "bob".to_string(),
+            image_url: "".to_string(),
+            identifier: "bob@example.com".to_string(),
+        });
+        let plugin = Plugin::new(&format!("btp+ws://{}", btp_address)).unwrap();
+        let (payments, received) = mpsc::channel();
+        // hyper's errors aren't Send, so the result can't leave the thread
+        thread::spawn(move || {
+            let _ = spsp_server.run_with_handler(&http_address, plugin, move |payment| {
+                payments.send(payment.clone()).unwrap();
+            });
+        });
+
+        let mut connection = btp_server.accept()
+            .unwrap_or_else(|_| panic!("plugin did not connect to the BTP server"))
+            .accept().unwrap();
+
+        // The plugin authenticates before it listens
+        let auth = match connection.recv_message().unwrap() {
+            OwnedMessage::Binary(bytes) => BtpPacket::from_bytes(&bytes).unwrap(),
+            other => panic!("expected auth message, got {:?}", other),
+        };
+        let auth_response = BtpPacket {
+            packet_type: PacketType::Response,
+            request_id: auth.request_id,
+            data: PacketContents::Response(Response {
+                protocol_data: Vec::new(),
+            }),
+        };
+        connection.send_message(&OwnedMessage::Binary(auth_response.to_bytes().unwrap())).unwrap();
+
+        // A malformed packet gets a BTP Error and the plugin keeps listening
+        connection.send_message(&OwnedMessage::Binary(vec![3, 0, 0, 0, 9, 0xff])).unwrap();
+        match next_result(&mut connection) {
+            PacketContents::ErrorResponse(error) => assert_eq!(error.code, "F01"),
+            other => panic!("expected error, got {:?}", other),
+        }
+
+        let details = query_with_retries(&format!("http://{}/bob", http_address));
+        assert!(details.destination_account.starts_with("example.bob."));
+        assert_eq!(details.receiver_info.identifier, "bob@example.com");
+        let shared_secret = base64::decode_config(&details.shared_secret, base64::URL_SAFE_NO_PAD).unwrap();
+        let memo = psk::PskMemo {
+            data: b"hello".to_vec(),
+            ..psk::PskMemo::default()
+        };
+        let (packet, condition) = psk::create_packet_and_condition_with_memo(
+            &shared_secret, &details.destination_account, 100, &memo, psk::Encryption::Aes256Gcm).unwrap();
+
+        send_prepare(&mut connection, [1; 16], 100, condition, packet.clone());
+        match next_result(&mut connection) {
+            PacketContents::Fulfill(fulfill) => {
+                assert_eq!(fulfill.transfer_id, [1; 16]);
+                assert_eq!(digest::digest(&digest::SHA256, &fulfill.fulfillment).as_ref(), &condition[..]);
+            },
+            other => panic!("expected fulfill, got {:?}", other),
+        }
+        let payment = received.recv_timeout(StdDuration::from_secs(5)).unwrap();
+        assert_eq!(payment.transfer_id, Some([1; 16]));
+        assert_eq!(payment.amount, 100);
+        assert_eq!(payment.memo.data, b"hello".to_vec());
+
+        send_prepare(&mut connection, [2; 16], 100, [0; 32], packet);
+        match next_result(&mut connection) {
+            PacketContents::Reject(reject) => assert_eq!(reject.transfer_id, [2; 16]),
+            other => panic!("expected reject, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod receiving {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn rejects_expired_transfers() {
+        let mut server = SpspServer::new("example.bob", LedgerInfo {
+            currency_code: "USD".to_string(),
+            currency_scale: 2,
+        });
+        server.add_receiver(ReceiverInfo {
+            name: "bob".to_string(),
+            image_url: "".to_string(),
+            identifier: "bob@example.com".to_string(),
+        });
+        let details = server.spsp_details("bob").unwrap();
+        let shared_secret = base64::decode_config(&details.shared_secret, base64::URL_SAFE_NO_PAD).unwrap();
+        let (packet, condition) = psk::create_packet_and_condition(&shared_secret, &details.destination_account, 100);
+        let mut transfer = Transfer {
+            id: [1; 16],
+            amount: 100,
+            ilp: packet,
+            execution_condition: condition,
+            expires_at: Utc::now().checked_add_signed(Duration::seconds(30)).unwrap().to_rfc3339(),
+        };
+        assert!(server.receive(&transfer).is_some());
+        transfer.expires_at = Utc::now().checked_sub_signed(Duration::seconds(1)).unwrap().to_rfc3339();
+        assert!(server.receive(&transfer).is_none());
+    }
+}
@@ -0,0 +1,885 @@
+use std::io::{Cursor, Error as IoError};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::mem;
+use ilp_packet;
+use ilp_packet::oer::WriteOerExt;
+use byteorder::{WriteBytesExt, ReadBytesExt};
+use ring::{hmac, digest};
+use uuid::Uuid;
+use chrono::{Utc, Duration};
+use btp_packet::{ProtocolData, Error as BtpError, read_var_uint, write_var_uint, read_var_octet_slice};
+use plugin;
+use plugin::{IlpPlugin, Transfer, TransferResult, find_response_data};
+use ilpv4::read_ilp_payment;
+use psk2;
+
+// Name of the protocol data entry that carries the receiver's response in Fulfills and Rejects
+pub const PROTOCOL_NAME: &str = "stream";
+const ENCRYPTION_KEY_STRING: &[u8] = b"ilp_stream_encryption";
+const FULFILLMENT_GENERATION_STRING: &[u8] = b"ilp_stream_fulfillment";
+const VERSION: u8 = 1;
+// Bytes of incoming data buffered per stream before the sender has to wait for them to be read
+const MAX_BUFFERED_DATA: u64 = 16384;
+const MAX_DATA_PER_PACKET: usize = 8192;
+// Seconds each packet's transfer is held for
+const PACKET_HOLD_DURATION: i64 = 30;
+const MAX_REMOTE_STREAMS: usize = 64;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: IoError) {
+            description(err.description())
+            from()
+        }
+        Plugin(err: plugin::Error) {
+            description(err.description())
+            from()
+        }
+        InvalidPacket(err: ilp_packet::errors::ParseError) {
+            description(err.description())
+            from()
+        }
+        Encryption(err: psk2::Error) {
+            description(err.description())
+            from()
+        }
+        InvalidEncoding(err: BtpError) {
+            description(err.description())
+            from()
+        }
+        Invalid(descr: &'static str) {
+            description(descr)
+        }
+        UnknownStream(stream_id: u64) {
+            description("no stream with that id")
+        }
+        StreamClosed(stream_id: u64) {
+            description("stream is closed")
+        }
+        ConnectionClosed {
+            description("connection is closed")
+        }
+        ExceedsReceiveMax(stream_id: u64) {
+            description("the receiver will not accept that much money on the stream")
+        }
+        FlowControl(stream_id: u64) {
+            description("the receiver's buffer for the stream is full")
+        }
+        Rejected {
+            description("the receiver rejected the packet")
+        }
+        NoExchangeRate {
+            description("min_exchange_rate must be set before sending money")
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum StreamPacketType {
+    Prepare = 12,
+    Fulfill = 13,
+    Reject = 14,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Frame {
+    ConnectionClose { code: u8, message: String },
+    StreamClose { stream_id: u64, code: u8, message: String },
+    StreamMoney { stream_id: u64, shares: u64 },
+    StreamMaxMoney { stream_id: u64, receive_max: u64, total_received: u64 },
+    StreamData { stream_id: u64, offset: u64, data: Vec<u8> },
+    StreamMaxData { stream_id: u64, max_offset: u64 },
+    StreamDataBlocked { stream_id: u64, max_offset: u64 },
+    // Frames we don't understand are skipped rather than failing the whole packet
+    Unknown { frame_type: u8, contents: Vec<u8> },
+}
+
+fn read_utf8_string(reader: &mut Cursor<&[u8]>) -> Result<String, Error> {
+    String::from_utf8(read_var_octet_slice(reader)?.to_vec())
+        .map_err(|_| Error::Invalid("message must be UTF-8"))
+}
+
+impl Frame {
+    fn frame_type(&self) -> u8 {
+        match *self {
+            Frame::ConnectionClose { .. } => 0x01,
+            Frame::StreamClose { .. } => 0x10,
+            Frame::StreamMoney { .. } => 0x11,
+            Frame::StreamMaxMoney { .. } => 0x12,
+            Frame::StreamData { .. } => 0x14,
+            Frame::StreamMaxData { .. } => 0x15,
+            Frame::StreamDataBlocked { .. } => 0x16,
+            Frame::Unknown { frame_type, .. } => frame_type,
+        }
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) -> Result<(), Error> {
+        let mut contents: Vec<u8> = Vec::new();
+        match *self {
+            Frame::ConnectionClose { code, ref message } => {
+                contents.write_u8(code)?;
+                contents.write_var_octet_string(message.as_bytes())?;
+            },
+            Frame::StreamClose { stream_id, code, ref message } => {
+                write_var_uint(&mut contents, stream_id)?;
+                contents.write_u8(code)?;
+                contents.write_var_octet_string(message.as_bytes())?;
+            },
+            Frame::StreamMoney { stream_id, shares } => {
+                write_var_uint(&mut contents, stream_id)?;
+                write_var_uint(&mut contents, shares)?;
+            },
+            Frame::StreamMaxMoney { stream_id, receive_max, total_received } => {
+                write_var_uint(&mut contents, stream_id)?;
+                write_var_uint(&mut contents, receive_max)?;
+                write_var_uint(&mut contents, total_received)?;
+            },
+            Frame::StreamData { stream_id, offset, ref data } => {
+                write_var_uint(&mut contents, stream_id)?;
+                write_var_uint(&mut contents, offset)?;
+                contents.write_var_octet_string(data)?;
+            },
+            Frame::StreamMaxData { stream_id, max_offset } |
+            Frame::StreamDataBlocked { stream_id, max_offset } => {
+                write_var_uint(&mut contents, stream_id)?;
+                write_var_uint(&mut contents, max_offset)?;
+            },
+            Frame::Unknown { contents: ref unknown_contents, .. } => {
+                contents.extend_from_slice(unknown_contents);
+            },
+        }
+        bytes.write_u8(self.frame_type())?;
+        bytes.write_var_octet_string(&contents)?;
+        Ok(())
+    }
+
+    fn from_bytes(frame_type: u8, contents: &[u8]) -> Result<Frame, Error> {
+        let mut reader = Cursor::new(contents);
+        let frame = match frame_type {
+            0x01 => Frame::ConnectionClose {
+                code: reader.read_u8()?,
+                message: read_utf8_string(&mut reader)?,
+            },
+            0x10 => Frame::StreamClose {
+                stream_id: read_var_uint(&mut reader)?,
+                code: reader.read_u8()?,
+                message: read_utf8_string(&mut reader)?,
+            },
+            0x11 => Frame::StreamMoney {
+                stream_id: read_var_uint(&mut reader)?,
+                shares: read_var_uint(&mut reader)?,
+            },
+            0x12 => Frame::StreamMaxMoney {
+                stream_id: read_var_uint(&mut reader)?,
+                receive_max: read_var_uint(&mut reader)?,
+                total_received: read_var_uint(&mut reader)?,
+            },
+            0x14 => Frame::StreamData {
+                stream_id: read_var_uint(&mut reader)?,
+                offset: read_var_uint(&mut reader)?,
+                data: read_var_octet_slice(&mut reader)?.to_vec(),
+            },
+            0x15 => Frame::StreamMaxData {
+                stream_id: read_var_uint(&mut reader)?,
+                max_offset: read_var_uint(&mut reader)?,
+            },
+            0x16 => Frame::StreamDataBlocked {
+                stream_id: read_var_uint(&mut reader)?,
+                max_offset: read_var_uint(&mut reader)?,
+            },
+            _ => Frame::Unknown {
+                frame_type,
+                contents: contents.to_vec(),
+            },
+        };
+        Ok(frame)
+    }
+}
+
+// Encrypted and carried in the data of an ILP payment, or in the response to one
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamPacket {
+    pub packet_type: StreamPacketType,
+    pub sequence: u64,
+    // For Prepares, the minimum amount the receiver should get. For Fulfills and Rejects,
+    // the amount the receiver actually got
+    pub prepare_amount: u64,
+    pub frames: Vec<Frame>,
+}
+
+impl StreamPacket {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.write_u8(VERSION)?;
+        bytes.write_u8(self.packet_type as u8)?;
+        write_var_uint(&mut bytes, self.sequence)?;
+        write_var_uint(&mut bytes, self.prepare_amount)?;
+        write_var_uint(&mut bytes, self.frames.len() as u64)?;
+        for frame in &self.frames {
+            frame.write_to(&mut bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<StreamPacket, Error> {
+        let mut reader = Cursor::new(bytes);
+        if reader.read_u8()? != VERSION {
+            return Err(Error::Invalid("unsupported STREAM version"));
+        }
+        let packet_type = match reader.read_u8()? {
+            12 => StreamPacketType::Prepare,
+            13 => StreamPacketType::Fulfill,
+            14 => StreamPacketType::Reject,
+            _ => return Err(Error::Invalid("unknown STREAM packet type")),
+        };
+        let sequence = read_var_uint(&mut reader)?;
+        let prepare_amount = read_var_uint(&mut reader)?;
+        let num_frames = read_var_uint(&mut reader)?;
+        let mut frames = Vec::new();
+        for _i in 0..num_frames {
+            let frame_type = reader.read_u8()?;
+            let contents = read_var_octet_slice(&mut reader)?;
+            frames.push(Frame::from_bytes(frame_type, contents)?);
+        }
+        Ok(StreamPacket {
+            packet_type,
+            sequence,
+            prepare_amount,
+            frames,
+        })
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let s_key = hmac::SigningKey::new(&digest::SHA256, key);
+    hmac::sign(&s_key, message).as_ref().to_vec()
+}
+
+fn data_to_fulfillment(shared_secret: &[u8], data: &[u8]) -> [u8; 32] {
+    let fulfillment_key = hmac(shared_secret, FULFILLMENT_GENERATION_STRING);
+    let mut fulfillment = [0u8; 32];
+    fulfillment.copy_from_slice(&hmac(&fulfillment_key, data));
+    fulfillment
+}
+
+#[derive(Debug)]
+pub struct Stream {
+    pub id: u64,
+    pub total_sent: u64,
+    pub total_received: u64,
+    // The most this side will accept on the stream
+    pub receive_max: u64,
+    remote_receive_max: u64,
+    // Received but not yet read
+    incoming_data: Vec<u8>,
+    incoming_offset: u64,
+    read_offset: u64,
+    outgoing_offset: u64,
+    remote_max_offset: u64,
+    pub closed: bool,
+    pub remote_closed: bool,
+}
+
+impl Stream {
+    fn new(id: u64) -> Self {
+        Stream {
+            id,
+            total_sent: 0,
+            total_received: 0,
+            receive_max: u64::max_value(),
+            remote_receive_max: u64::max_value(),
+            incoming_data: Vec::new(),
+            incoming_offset: 0,
+            read_offset: 0,
+            outgoing_offset: 0,
+            remote_max_offset: MAX_BUFFERED_DATA,
+            closed: false,
+            remote_closed: false,
+        }
+    }
+
+    fn max_offset(&self) -> u64 {
+        self.read_offset + MAX_BUFFERED_DATA
+    }
+}
+
+// Splits the amount between the money frames according to their shares.
+// The remainder from rounding goes to the last stream
+fn split_amount(frames: &[Frame], amount: u64) -> Vec<(u64, u64)> {
+    let money: Vec<(u64, u64)> = frames.iter().filter_map(|frame| match *frame {
+        Frame::StreamMoney { stream_id, shares } => Some((stream_id, shares)),
+        _ => None,
+    }).collect();
+    let total_shares = money.iter().fold(0u64, |total, &(_, shares)| total.saturating_add(shares));
+    let mut remaining = amount;
+    let mut split = Vec::new();
+    for (i, &(stream_id, shares)) in money.iter().enumerate() {
+        let share = if i == money.len() - 1 || total_shares == 0 {
+            remaining
+        } else {
+            (amount as f64 * shares as f64 / total_shares as f64).floor() as u64
+        };
+        let share = if share > remaining { remaining } else { share };
+        remaining -= share;
+        split.push((stream_id, share));
+    }
+    split
+}
+
+// Multiplexes streams of money and data over ILP payments to a single counterparty
+pub struct StreamConnection<P: IlpPlugin> {
+    // Minimum destination units the other side should get per unit we send. Packets that
+    // carry money can't be sent until this is set
+    pub min_exchange_rate: Option<f64>,
+    // Packets that would make the other side open more streams than this are rejected
+    pub max_remote_streams: usize,
+    plugin: P,
+    destination_account: String,
+    shared_secret: Vec<u8>,
+    next_stream_id: u64,
+    next_sequence: u64,
+    streams: BTreeMap<u64, Stream>,
+    incoming_streams: VecDeque<u64>,
+    closed: bool,
+}
+
+impl<P: IlpPlugin> StreamConnection<P> {
+    // Both sides use the same shared secret. The client opens odd-numbered streams and the
+    // server even-numbered ones so that their ids never collide
+    pub fn new(plugin: P, destination_account: &str, shared_secret: &[u8], is_server: bool) -> Self {
+        StreamConnection {
+            min_exchange_rate: None,
+            max_remote_streams: MAX_REMOTE_STREAMS,
+            plugin,
+            destination_account: destination_account.to_string(),
+            shared_secret: shared_secret.to_vec(),
+            next_stream_id: if is_server { 2 } else { 1 },
+            next_sequence: 1,
+            streams: BTreeMap::new(),
+            incoming_streams: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    pub fn open_stream(&mut self) -> u64 {
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 2;
+        self.streams.insert(stream_id, Stream::new(stream_id));
+        stream_id
+    }
+
+    // Returns the id of the next stream the other side opened, if there is one
+    pub fn accept_stream(&mut self) -> Option<u64> {
+        self.incoming_streams.pop_front()
+    }
+
+    pub fn stream(&self, stream_id: u64) -> Option<&Stream> {
+        self.streams.get(&stream_id)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn get_open_stream(&mut self, stream_id: u64) -> Result<&mut Stream, Error> {
+        match self.streams.get_mut(&stream_id) {
+            Some(ref stream) if stream.closed || stream.remote_closed => Err(Error::StreamClosed(stream_id)),
+            Some(stream) => Ok(stream),
+            None => Err(Error::UnknownStream(stream_id)),
+        }
+    }
+
+    pub fn set_receive_max(&mut self, stream_id: u64, receive_max: u64) -> Result<(), Error> {
+        let stream = self.streams.get_mut(&stream_id).ok_or(Error::UnknownStream(stream_id))?;
+        stream.receive_max = receive_max;
+        Ok(())
+    }
+
+    // Returns all of the data received on the stream since the last read and lets the
+    // sender send that much more
+    pub fn read_data(&mut self, stream_id: u64) -> Result<Vec<u8>, Error> {
+        let stream = self.streams.get_mut(&stream_id).ok_or(Error::UnknownStream(stream_id))?;
+        let data = mem::replace(&mut stream.incoming_data, Vec::new());
+        stream.read_offset += data.len() as u64;
+        Ok(data)
+    }
+
+    pub fn send_money(&mut self, stream_id: u64, amount: u64) -> Result<(), Error> {
+        {
+            let stream = self.get_open_stream(stream_id)?;
+            if stream.total_sent.saturating_add(amount) > stream.remote_receive_max {
+                return Err(Error::ExceedsReceiveMax(stream_id));
+            }
+        }
+        self.send_packet(amount, vec![Frame::StreamMoney {
+            stream_id,
+            shares: 1,
+        }])?;
+        self.get_open_stream(stream_id)?.total_sent += amount;
+        Ok(())
+    }
+
+    pub fn send_data(&mut self, stream_id: u64, data: &[u8]) -> Result<(), Error> {
+        let (outgoing_offset, remote_max_offset) = {
+            let stream = self.get_open_stream(stream_id)?;
+            (stream.outgoing_offset, stream.remote_max_offset)
+        };
+        if outgoing_offset + data.len() as u64 > remote_max_offset {
+            // Ask whether the receiver has made room since we last heard from it
+            self.send_packet(0, vec![Frame::StreamDataBlocked {
+                stream_id,
+                max_offset: remote_max_offset,
+            }])?;
+            if outgoing_offset + data.len() as u64 > self.get_open_stream(stream_id)?.remote_max_offset {
+                return Err(Error::FlowControl(stream_id));
+            }
+        }
+
+        for chunk in data.chunks(MAX_DATA_PER_PACKET) {
+            let offset = self.get_open_stream(stream_id)?.outgoing_offset;
+            self.send_packet(0, vec![Frame::StreamData {
+                stream_id,
+                offset,
+                data: chunk.to_vec(),
+            }])?;
+            self.get_open_stream(stream_id)?.outgoing_offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub fn close_stream(&mut self, stream_id: u64) -> Result<(), Error> {
+        self.get_open_stream(stream_id)?;
+        self.send_packet(0, vec![Frame::StreamClose {
+            stream_id,
+            code: 0,
+            message: String::new(),
+        }])?;
+        self.get_open_stream(stream_id)?.closed = true;
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.send_packet(0, vec![Frame::ConnectionClose {
+            code: 0,
+            message: String::new(),
+        }])?;
+        self.closed = true;
+        Ok(())
+    }
+
+    fn encryption_key(&self) -> Vec<u8> {
+        hmac(&self.shared_secret, ENCRYPTION_KEY_STRING)
+    }
+
+    fn send_packet(&mut self, amount: u64, frames: Vec<Frame>) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::ConnectionClosed);
+        }
+        // Without a minimum, connectors on the path could keep the money
+        let prepare_amount = match self.min_exchange_rate {
+            _ if amount == 0 => 0,
+            Some(rate) => (amount as f64 * rate).floor() as u64,
+            None => return Err(Error::NoExchangeRate),
+        };
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let packet = StreamPacket {
+            packet_type: StreamPacketType::Prepare,
+            sequence,
+            prepare_amount,
+            frames,
+        };
+        let data = psk2::seal(&self.encryption_key(), &packet.to_bytes()?);
+        let fulfillment = data_to_fulfillment(&self.shared_secret, &data);
+        let mut condition = [0u8; 32];
+        condition.copy_from_slice(digest::digest(&digest::SHA256, &fulfillment).as_ref());
+        let ilp = ilp_packet::packet::IlpPayment {
+            account: self.destination_account.to_string(),
+            amount,
+            data,
+        }.to_bytes()?;
+        let transfer = Transfer {
+            id: *Uuid::new_v4().as_bytes(),
+            amount,
+            ilp,
+            execution_condition: condition,
+            expires_at: Utc::now().checked_add_signed(Duration::seconds(PACKET_HOLD_DURATION)).unwrap().to_rfc3339(),
+        };
+
+        let (fulfilled, protocol_data) = match self.plugin.send_transfer(transfer)? {
+            TransferResult::Fulfilled { protocol_data, .. } => (true, protocol_data),
+            TransferResult::Rejected { protocol_data, .. } => (false, protocol_data),
+        };
+        let response = find_response_data(&protocol_data, PROTOCOL_NAME)
+            .and_then(|p| psk2::open(&self.encryption_key(), &p.data).ok())
+            .and_then(|bytes| StreamPacket::from_bytes(&bytes).ok());
+        if let Some(response) = response {
+            if response.sequence == sequence {
+                self.handle_remote_frames(&response.frames);
+            }
+        }
+        if fulfilled {
+            Ok(())
+        } else {
+            Err(Error::Rejected)
+        }
+    }
+
+    // Applies the limits and close notifications the other side sent us
+    fn handle_remote_frames(&mut self, frames: &[Frame]) {
+        for frame in frames {
+            match *frame {
+                Frame::ConnectionClose { .. } => self.closed = true,
+                Frame::StreamClose { stream_id, .. } => {
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.remote_closed = true;
+                    }
+                },
+                Frame::StreamMaxMoney { stream_id, receive_max, .. } => {
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.remote_receive_max = receive_max;
+                    }
+                },
+                Frame::StreamMaxData { stream_id, max_offset } => {
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        if max_offset > stream.remote_max_offset {
+                            stream.remote_max_offset = max_offset;
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    // Ids the other side may open: odd ones if we're the server, even ones if we're the client
+    fn is_remote_stream_id(&self, stream_id: u64) -> bool {
+        stream_id != 0 && stream_id % 2 != self.next_stream_id % 2
+    }
+
+    // Only call this after frames_fit has checked the stream ids
+    fn get_or_create_stream(&mut self, stream_id: u64) -> &mut Stream {
+        let incoming_streams = &mut self.incoming_streams;
+        self.streams.entry(stream_id).or_insert_with(|| {
+            incoming_streams.push_back(stream_id);
+            Stream::new(stream_id)
+        })
+    }
+
+    // Checks whether the money and data in the frames fit within our limits
+    fn frames_fit(&self, frames: &[Frame], amount: u64) -> bool {
+        if amount > 0 && !frames.iter().any(|frame| match *frame { Frame::StreamMoney { .. } => true, _ => false }) {
+            return false;
+        }
+        // Money and data are the frames that open streams
+        let new_stream_ids: BTreeSet<u64> = frames.iter().filter_map(|frame| match *frame {
+            Frame::StreamMoney { stream_id, .. } |
+            Frame::StreamData { stream_id, .. } => Some(stream_id),
+            _ => None,
+        }).filter(|stream_id| !self.streams.contains_key(stream_id)).collect();
+        if !new_stream_ids.iter().all(|&stream_id| self.is_remote_stream_id(stream_id)) {
+            return false;
+        }
+        let remote_streams = self.streams.keys().filter(|&&stream_id| self.is_remote_stream_id(stream_id)).count();
+        if remote_streams + new_stream_ids.len() > self.max_remote_streams {
+            return false;
+        }
+        for (stream_id, share) in split_amount(frames, amount) {
+            if let Some(stream) = self.streams.get(&stream_id) {
+                if stream.closed || stream.total_received.saturating_add(share) > stream.receive_max {
+                    return false;
+                }
+            }
+        }
+        for frame in frames {
+            if let Frame::StreamData { stream_id, offset, ref data } = *frame {
+                let (incoming_offset, max_offset, closed) = match self.streams.get(&stream_id) {
+                    Some(stream) => (stream.incoming_offset, stream.max_offset(), stream.closed),
+                    None => (0, MAX_BUFFERED_DATA, false),
+                };
+                if closed || offset > incoming_offset || offset + data.len() as u64 > max_offset {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn apply_frames(&mut self, frames: &[Frame], amount: u64) {
+        for (stream_id, share) in split_amount(frames, amount) {
+            self.get_or_create_stream(stream_id).total_received += share;
+        }
+        for frame in frames {
+            if let Frame::StreamData { stream_id, offset, ref data } = *frame {
+                let stream = self.get_or_create_stream(stream_id);
+                // Skip anything we already have in case a packet was retried
+                let end = offset + data.len() as u64;
+                if end > stream.incoming_offset {
+                    let skip = (stream.incoming_offset - offset) as usize;
+                    stream.incoming_data.extend_from_slice(&data[skip..]);
+                    stream.incoming_offset = end;
+                }
+            }
+        }
+        self.handle_remote_frames(frames);
+    }
+
+    // Tells the sender our current limits for every stream it mentioned
+    fn response_frames(&self, frames: &[Frame]) -> Vec<Frame> {
+        let stream_ids: BTreeSet<u64> = frames.iter().filter_map(|frame| match *frame {
+            Frame::StreamClose { stream_id, .. } |
+            Frame::StreamMoney { stream_id, .. } |
+            Frame::StreamData { stream_id, .. } |
+            Frame::StreamDataBlocked { stream_id, .. } => Some(stream_id),
+            _ => None,
+        }).collect();
+        let mut response = Vec::new();
+        for stream in stream_ids.iter().filter_map(|stream_id| self.streams.get(stream_id)) {
+            response.push(Frame::StreamMaxMoney {
+                stream_id: stream.id,
+                receive_max: stream.receive_max,
+                total_received: stream.total_received,
+            });
+            response.push(Frame::StreamMaxData {
+                stream_id: stream.id,
+                max_offset: stream.max_offset(),
+            });
+        }
+        response
+    }
+
+    // Pass every transfer the plugin receives from the other side to this, and send back
+    // the result
+    pub fn handle_prepare(&mut self, transfer: &Transfer) -> TransferResult {
+        match self.try_handle_prepare(transfer) {
+            Ok(result) => result,
+            Err(err) => {
+                debug!("rejecting incoming STREAM packet: {:?}", err);
+                TransferResult::Rejected {
+                    ilp_error: None,
+                    protocol_data: Vec::new(),
+                }
+            },
+        }
+    }
+
+    fn try_handle_prepare(&mut self, transfer: &Transfer) -> Result<TransferResult, Error> {
+        let payment = read_ilp_payment(&transfer.ilp)?;
+        let packet = StreamPacket::from_bytes(&psk2::open(&self.encryption_key(), &payment.data)?)?;
+        if packet.packet_type != StreamPacketType::Prepare {
+            return Err(Error::Invalid("expected a STREAM prepare"));
+        }
+        let fulfillment = data_to_fulfillment(&self.shared_secret, &payment.data);
+        let condition_matches = digest::digest(&digest::SHA256, &fulfillment).as_ref() == &transfer.execution_condition[..];
+
+        let accept = condition_matches
+            && !self.closed
+            && transfer.amount >= packet.prepare_amount
+            && self.frames_fit(&packet.frames, transfer.amount);
+        if accept {
+            self.apply_frames(&packet.frames, transfer.amount);
+        }
+
+        let response = StreamPacket {
+            packet_type: if accept { StreamPacketType::Fulfill } else { StreamPacketType::Reject },
+            sequence: packet.sequence,
+            prepare_amount: transfer.amount,
+            frames: self.response_frames(&packet.frames),
+        };
+        let protocol_data = vec![ProtocolData::octet_stream(PROTOCOL_NAME, psk2::seal(&self.encryption_key(), &response.to_bytes()?))];
+        if accept {
+            Ok(TransferResult::Fulfilled {
+                fulfillment,
+                protocol_data,
+            })
+        } else {
+            Ok(TransferResult::Rejected {
+                ilp_error: None,
+                protocol_data,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod packets {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_frame() {
+        let packet = StreamPacket {
+            packet_type: StreamPacketType::Prepare,
+            sequence: 7,
+            prepare_amount: 300,
+            frames: vec![
+                Frame::ConnectionClose { code: 1, message: "bye".to_string() },
+                Frame::StreamClose { stream_id: 1, code: 2, message: "done".to_string() },
+                Frame::StreamMoney { stream_id: 3, shares: 2 },
+                Frame::StreamMaxMoney { stream_id: 3, receive_max: u64::max_value(), total_received: 256 },
+                Frame::StreamData { stream_id: 5, offset: 70000, data: b"hello".to_vec() },
+                Frame::StreamMaxData { stream_id: 5, max_offset: 86384 },
+                Frame::StreamDataBlocked { stream_id: 5, max_offset: 70005 },
+                Frame::Unknown { frame_type: 0x40, contents: vec![1, 2, 3] },
+            ],
+        };
+        let bytes = packet.to_bytes().unwrap();
+        // Sequence and prepare amount are variable-length integers
+        assert_eq!(&bytes[..6], &[1, 12, 1, 7, 2, 1]);
+        assert_eq!(StreamPacket::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let packet = StreamPacket {
+            packet_type: StreamPacketType::Prepare,
+            sequence: 1,
+            prepare_amount: 0,
+            frames: vec![Frame::StreamData { stream_id: 1, offset: 0, data: b"hello".to_vec() }],
+        };
+        let mut bytes = packet.to_bytes().unwrap();
+        let length = bytes.len();
+        bytes.truncate(length - 2);
+        assert!(StreamPacket::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn splits_money_by_shares() {
+        let frames = vec![
+            Frame::StreamMoney { stream_id: 1, shares: 1 },
+            Frame::StreamMoney { stream_id: 3, shares: 2 },
+        ];
+        assert_eq!(split_amount(&frames, 100), vec![(1, 33), (3, 67)]);
+    }
+}
+
+#[cfg(test)]
+mod connection {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use plugin::MemoryPlugin;
+
+    const SHARED_SECRET: [u8; 32] = [5; 32];
+
+    fn connect() -> (StreamConnection<MemoryPlugin>, Arc<Mutex<StreamConnection<MemoryPlugin>>>) {
+        let (client_plugin, server_plugin) = MemoryPlugin::pair();
+        let server = Arc::new(Mutex::new(StreamConnection::new(server_plugin.clone(), "example.client", &SHARED_SECRET, true)));
+        let handler_server = server.clone();
+        server_plugin.set_incoming_handler(move |transfer| handler_server.lock().unwrap().handle_prepare(transfer));
+        let mut client = StreamConnection::new(client_plugin, "example.server", &SHARED_SECRET, false);
+        client.min_exchange_rate = Some(1.0);
+        (client, server)
+    }
+
+    #[test]
+    fn sends_money_and_data() {
+        let (mut client, server) = connect();
+        let stream_id = client.open_stream();
+        client.send_data(stream_id, b"hello").unwrap();
+        client.send_money(stream_id, 100).unwrap();
+
+        let mut server = server.lock().unwrap();
+        assert_eq!(server.accept_stream(), Some(stream_id));
+        assert_eq!(server.accept_stream(), None);
+        assert_eq!(server.read_data(stream_id).unwrap(), b"hello".to_vec());
+        assert_eq!(server.stream(stream_id).unwrap().total_received, 100);
+        assert_eq!(client.stream(stream_id).unwrap().total_sent, 100);
+    }
+
+    #[test]
+    fn respects_receive_max() {
+        let (mut client, server) = connect();
+        let stream_id = client.open_stream();
+        client.send_data(stream_id, b"invoice 1234").unwrap();
+        server.lock().unwrap().set_receive_max(stream_id, 50).unwrap();
+
+        match client.send_money(stream_id, 100) {
+            Err(Error::Rejected) => {},
+            other => panic!("expected Rejected, got {:?}", other),
+        }
+        // Now the client knows the limit and doesn't even try
+        match client.send_money(stream_id, 60) {
+            Err(Error::ExceedsReceiveMax(_)) => {},
+            other => panic!("expected ExceedsReceiveMax, got {:?}", other),
+        }
+        client.send_money(stream_id, 50).unwrap();
+        assert_eq!(server.lock().unwrap().stream(stream_id).unwrap().total_received, 50);
+    }
+
+    #[test]
+    fn waits_for_data_to_be_read() {
+        let (mut client, server) = connect();
+        let stream_id = client.open_stream();
+        let data = vec![1u8; MAX_BUFFERED_DATA as usize];
+        client.send_data(stream_id, &data).unwrap();
+        match client.send_data(stream_id, b"more") {
+            Err(Error::FlowControl(_)) => {},
+            other => panic!("expected FlowControl, got {:?}", other),
+        }
+
+        assert_eq!(server.lock().unwrap().read_data(stream_id).unwrap(), data);
+        client.send_data(stream_id, b"more").unwrap();
+        assert_eq!(server.lock().unwrap().read_data(stream_id).unwrap(), b"more".to_vec());
+    }
+
+    #[test]
+    fn enforces_exchange_rate() {
+        let (mut client, server) = connect();
+        let stream_id = client.open_stream();
+        client.min_exchange_rate = None;
+        match client.send_money(stream_id, 10) {
+            Err(Error::NoExchangeRate) => {},
+            other => panic!("expected NoExchangeRate, got {:?}", other),
+        }
+        // The memory plugin delivers the amount sent, which is less than the minimum
+        client.min_exchange_rate = Some(2.0);
+        match client.send_money(stream_id, 10) {
+            Err(Error::Rejected) => {},
+            other => panic!("expected Rejected, got {:?}", other),
+        }
+        assert!(server.lock().unwrap().stream(stream_id).is_none());
+    }
+
+    #[test]
+    fn rejects_streams_the_client_cannot_open() {
+        let (mut client, server) = connect();
+        // Even ids belong to the server
+        client.next_stream_id = 2;
+        let stream_id = client.open_stream();
+        match client.send_money(stream_id, 10) {
+            Err(Error::Rejected) => {},
+            other => panic!("expected Rejected, got {:?}", other),
+        }
+        assert!(server.lock().unwrap().stream(stream_id).is_none());
+    }
+
+    #[test]
+    fn limits_remote_streams() {
+        let (mut client, server) = connect();
+        server.lock().unwrap().max_remote_streams = 1;
+        let first = client.open_stream();
+        client.send_money(first, 10).unwrap();
+        let second = client.open_stream();
+        match client.send_data(second, b"hello") {
+            Err(Error::Rejected) => {},
+            other => panic!("expected Rejected, got {:?}", other),
+        }
+        let mut server = server.lock().unwrap();
+        assert_eq!(server.accept_stream(), Some(first));
+        assert_eq!(server.accept_stream(), None);
+    }
+
+    #[test]
+    fn closes_streams() {
+        let (mut client, server) = connect();
+        let stream_id = client.open_stream();
+        client.send_money(stream_id, 10).unwrap();
+        client.close_stream(stream_id).unwrap();
+        assert!(server.lock().unwrap().stream(stream_id).unwrap().remote_closed);
+        match client.send_money(stream_id, 10) {
+            Err(Error::StreamClosed(_)) => {},
+            other => panic!("expected StreamClosed, got {:?}", other),
+        }
+    }
+}